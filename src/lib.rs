@@ -1,15 +1,50 @@
 //! A datastructure for items that expire.
 
-use fnv::{FnvHashMap, FnvHasher};
-use std::{
-    collections::{
-        HashMap,
-        hash_map::{Iter as HashMapIter, IterMut as HashMapIterMut},
-    },
-    hash::BuildHasherDefault,
+// The crate writes explicit `return` statements throughout; keep clippy from flagging them.
+#![allow(clippy::needless_return)]
+#![cfg_attr(not(feature = "std"), no_std)]
+
+#[cfg(not(feature = "alloc"))]
+compile_error!("expiration_list needs either the `std` or the `alloc` feature");
+
+extern crate alloc;
+
+use alloc::{
+    boxed::Box,
+    collections::{BinaryHeap, TryReserveError},
+    sync::Arc,
+    vec,
+    vec::{Drain, IntoIter, Vec},
+};
+#[cfg(feature = "serde_support")]
+use alloc::{format, string::String};
+use core::{
+    cmp::{Ordering, Reverse},
+    error::Error,
+    fmt,
+    hash::{BuildHasher, BuildHasherDefault, Hash},
+    iter::FusedIterator,
+    ops::{Bound, Index, IndexMut, Range, RangeBounds},
+    panic::{RefUnwindSafe, UnwindSafe},
     slice::{Iter, IterMut},
+    sync::atomic::{AtomicUsize, Ordering as AtomicOrdering},
+    task::Poll,
+};
+use fnv::FnvHasher;
+use hashbrown::{
+    HashMap, HashSet,
+    hash_map::{
+        Drain as HashMapDrain, IntoIter as HashMapIntoIter, Iter as HashMapIter,
+        IterMut as HashMapIterMut,
+    },
 };
 
+/// The `hashbrown` crate, whose `HashMap` and `HashSet` appear in the API with and without `std`.
+pub use hashbrown;
+
+/// A `HashMap` using FNV.
+type FnvHashMap<K, V> = HashMap<K, V, BuildHasherDefault<FnvHasher>>;
+
 /// `ExpirationList` is more performant than a `HashMap` for items that are likely to be removed
 /// over time and require a stable ID which can be a `usize`. It does not automatically remove
 /// items or track expiry. It should not be used when the expiration is fixed, in this case
@@ -57,6 +92,21 @@ use std::{
 /// # }
 /// ```
 ///
+/// Tracking IDs without values:
+/// ```
+/// use expiration_list::ExpirationList;
+/// # fn main() {
+///
+/// // With `T = ()` each slot in the `Vec` is a single byte, so the list works as a set of IDs
+/// let mut ids = ExpirationList::new();
+/// let first = ids.add(());
+/// let second = ids.add(());
+/// ids.remove(first);
+/// assert!(!ids.contains(first));
+/// assert!(ids.contains(second));
+/// # }
+/// ```
+///
 /// # Implementation
 ///
 /// `ExpirationList` stores new items in a `Vec<Option<T>>`. Removing an item sets it to None.
@@ -78,26 +128,407 @@ use std::{
 /// performs like a `Vec` with some extra branching and O(1) removal time. In the worse case,
 /// `ExpirationList` performs like a `HashMap` with some extra branching.
 #[derive(Debug)]
+pub struct ExpirationList<T, S = BuildHasherDefault<FnvHasher>> {
+    first_id: usize,
+    count: usize,
+    list: Vec<Option<T>>,
+    map: HashMap<usize, T, S>,
+    base_id: usize,
+    free_slots: Option<BinaryHeap<Reverse<usize>>>,
+    map_ratio_cap: Option<f64>,
+    hysteresis: Option<(f64, f64)>,
+    shrink_policy: ShrinkPolicy,
+    in_place_shrink: bool,
+    auto_compact: Option<usize>,
+    slow_hits: AtomicUsize,
+    shrink_armed: bool,
+    last_shrink_len: usize,
+    shrink_visits: usize,
+    removals: usize,
+    pinned: Vec<Range<usize>>,
+    grow_hook: Option<Hook<dyn Fn(usize, usize) + Send + Sync + UnwindSafe + RefUnwindSafe>>,
+    spill_hook: Option<Hook<dyn FnMut(usize, T) + Send + Sync + UnwindSafe + RefUnwindSafe>>,
+}
+
+/// A callback registered on an `ExpirationList`. Callbacks are not serialized.
+struct Hook<F: ?Sized>(Box<F>);
+
+impl<F: ?Sized> fmt::Debug for Hook<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Hook")
+    }
+}
+
+/// The inner structure that an item of an `ExpirationList` is stored in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Region {
+    /// The item is stored in the `Vec` and is accessed without hashing.
+    List,
+    /// The item was moved to the `HashMap` by a shrink.
+    Map,
+}
+
+/// When an `ExpirationList` shrinks its `Vec`. A `Vec` longer than `min_len` slots is shrunk once
+/// fewer than `fill_ratio` of its slots hold items. The default shrinks `Vec`s longer than 32
+/// slots that are less than half full. A `fill_ratio` of 0 never shrinks.
+#[derive(Debug, Clone, Copy, PartialEq)]
 #[cfg_attr(
     feature = "serde_support",
     derive(serde::Serialize, serde::Deserialize)
 )]
-pub struct ExpirationList<T> {
-    first_id: usize,
-    count: usize,
-    list: Vec<Option<T>>,
-    map: HashMap<usize, T, BuildHasherDefault<FnvHasher>>,
+pub struct ShrinkPolicy {
+    /// The `Vec` is never shrunk while it has at most this many slots.
+    pub min_len: usize,
+    /// The `Vec` is shrunk once fewer than this fraction of its slots hold items.
+    pub fill_ratio: f64,
+}
+
+impl Default for ShrinkPolicy {
+    fn default() -> Self {
+        ShrinkPolicy {
+            min_len: 32,
+            fill_ratio: 0.5,
+        }
+    }
+}
+
+/// Every setting of an `ExpirationList`, for `ExpirationList::with_config`. Each field has the
+/// same effect as the `with_*` constructor of the same name, and the default matches
+/// `ExpirationList::new`.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+#[cfg_attr(
+    feature = "serde_support",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
+pub struct Config {
+    /// When the `Vec` shrinks, see `with_policy`.
+    pub shrink_policy: ShrinkPolicy,
+    /// The low and high watermarks, see `with_hysteresis`.
+    pub hysteresis: Option<(f64, f64)>,
+    /// The most items the `HashMap` may hold per slot of the `Vec`, see `with_map_ratio_cap`.
+    pub map_ratio_cap: Option<f64>,
+    /// Whether the `Vec` shrinks in place, see `with_in_place_shrink`.
+    pub in_place_shrink: bool,
+    /// The number of lookups in a row in the `HashMap` that compact it, see `with_auto_compact`.
+    pub auto_compact: Option<usize>,
+    /// Whether the IDs of removed items are reused, see `with_free_list`.
+    pub free_list: bool,
+}
+
+/// Where the items of an `ExpirationList` are stored, returned by `ExpirationList::stats`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    /// The number of slots in the `Vec`, including empty ones.
+    pub list_len: usize,
+    /// The capacity of the `Vec`.
+    pub list_capacity: usize,
+    /// The number of items in the `HashMap`.
+    pub map_len: usize,
+    /// The number of items, the same as `len`.
+    pub live_count: usize,
+    /// The number of empty slots in the `Vec`.
+    pub dead_slots: usize,
+    /// The ID of the first slot in the `Vec`.
+    pub first_id: usize,
+    /// The number of IDs that have an item in both the `Vec` and the `HashMap`, which can only
+    /// happen through `from_parts`. `ExpirationListStrictIter` skips the copy in the `HashMap`.
+    pub duplicate_ids: usize,
+    /// The number of slots visited by shrinks so far, see `shrink_visit_ratio`.
+    pub shrink_visits: usize,
+    /// The number of items removed so far, see `shrink_visit_ratio`.
+    pub removals: usize,
+}
+
+/// A difference between two `ExpirationList`s that share one ID space. Yielded by `diff`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change<'a, T> {
+    /// The item only exists in the newer list.
+    Added(usize, &'a T),
+    /// The item only exists in the previous list.
+    Removed(usize),
+    /// The item exists in both lists but its value changed to the one given.
+    Modified(usize, &'a T),
+}
+
+/// Which of two `ExpirationList`s an item yielded by `merge_iter` came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MergeSide<V> {
+    /// Only the list that `merge_iter` was called on has the ID.
+    Left(V),
+    /// Only the other list has the ID.
+    Right(V),
+    /// Both lists have the ID.
+    Both(V, V),
+}
+
+/// An internal inconsistency found by `ExpirationList::validate`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValidationError {
+    /// The stored count of items in the `Vec` does not match the number of occupied slots.
+    CountMismatch { count: usize, occupied: usize },
+    /// The `HashMap` holds an ID that belongs to the `Vec`.
+    MapIdInList(usize),
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValidationError::CountMismatch { count, occupied } => write!(
+                f,
+                "count is {count} but {occupied} slots of the list are occupied"
+            ),
+            ValidationError::MapIdInList(id) => {
+                write!(f, "id {id} is in the map but belongs to the list")
+            }
+        }
+    }
+}
+
+impl Error for ValidationError {}
+
+/// The error returned by `ExpirationList::try_add_iter` when the inner `Vec` could not grow or
+/// the IDs ran out.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TryAddError {
+    /// The number of items that were added before adding failed.
+    pub added: usize,
+    /// Why adding failed.
+    pub error: TryAddCause,
+}
+
+/// Why `ExpirationList::try_add_iter` stopped adding items.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TryAddCause {
+    /// The inner `Vec` could not grow.
+    Alloc(TryReserveError),
+    /// Every ID up to `usize::MAX` has been handed out.
+    Capacity(CapacityError),
+}
+
+impl fmt::Display for TryAddError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.error {
+            TryAddCause::Alloc(_) => {
+                write!(f, "failed to allocate after adding {} items", self.added)
+            }
+            TryAddCause::Capacity(_) => {
+                write!(f, "ran out of IDs after adding {} items", self.added)
+            }
+        }
+    }
 }
 
+impl Error for TryAddError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match &self.error {
+            TryAddCause::Alloc(error) => Some(error),
+            TryAddCause::Capacity(error) => Some(error),
+        }
+    }
+}
+
+/// The error returned by `ExpirationList::try_add` when every ID up to `usize::MAX` has been
+/// handed out, so a new item would get an ID that wrapped around.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CapacityError;
+
+impl fmt::Display for CapacityError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "no IDs are left below usize::MAX")
+    }
+}
+
+impl Error for CapacityError {}
+
+/// The reason that `try_remove` could not remove an item.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RemoveError {
+    /// The ID has not been handed out by `add` yet.
+    NeverIssued,
+    /// The item with this ID was already removed.
+    AlreadyRemoved,
+}
+
+impl fmt::Display for RemoveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RemoveError::NeverIssued => write!(f, "the id was never issued"),
+            RemoveError::AlreadyRemoved => write!(f, "the item was already removed"),
+        }
+    }
+}
+
+impl Error for RemoveError {}
+
 pub struct ExpirationListIter<'a, T> {
     map_iter: Option<HashMapIter<'a, usize, T>>,
     list_iter: Iter<'a, Option<T>>,
     list_id: usize,
+    remaining: usize,
 }
 pub struct ExpirationListIterMut<'a, T> {
     map_iter: Option<HashMapIterMut<'a, usize, T>>,
     list_iter: IterMut<'a, Option<T>>,
     list_id: usize,
+    remaining: usize,
+}
+pub struct ExpirationListIntoIter<T> {
+    map_iter: Option<HashMapIntoIter<usize, T>>,
+    list_iter: IntoIter<Option<T>>,
+    list_id: usize,
+    remaining: usize,
+}
+
+/// Removes and yields every item of an `ExpirationList`. Created by `ExpirationList::drain`.
+/// Items that have not been yielded when the iterator is dropped are dropped with it.
+pub struct ExpirationListDrain<'a, T> {
+    map_iter: HashMapDrain<'a, usize, T>,
+    list_iter: Drain<'a, Option<T>>,
+    list_id: usize,
+}
+
+/// Iterates over the items of an `ExpirationList` like `ExpirationListIter` but skips IDs that are
+/// stored in both the `Vec` and the `HashMap`. Created by `ExpirationList::iter_strict`.
+pub struct ExpirationListStrictIter<'a, T, S = BuildHasherDefault<FnvHasher>> {
+    iter: ExpirationListIter<'a, T>,
+    list: &'a ExpirationList<T, S>,
+    anomalies: usize,
+}
+
+impl<T, S> ExpirationListStrictIter<'_, T, S> {
+    /// Returns the number of duplicate IDs that have been skipped so far.
+    pub fn anomalies(&self) -> usize {
+        self.anomalies
+    }
+}
+
+/// Iterates over the items with IDs in a range in ascending ID order. Created by
+/// `ExpirationList::iter_range`.
+pub struct ExpirationListRangeIter<'a, T, S = BuildHasherDefault<FnvHasher>> {
+    list: &'a ExpirationList<T, S>,
+    map_ids: Vec<usize>,
+    map_pos: usize,
+    list_id: usize,
+    end_id: usize,
+}
+
+impl<T, S> ExpirationListRangeIter<'_, T, S> {
+    /// Returns the ID of the item that the iterator will yield next, or the end of its range when
+    /// it is exhausted. The returned ID can be passed to `iter_range` to resume iterating later.
+    pub fn current_id(&self) -> usize {
+        if let Some(id) = self.map_ids.get(self.map_pos) {
+            return *id;
+        }
+        return (self.list_id..self.end_id)
+            .find(|id| matches!(self.list.list.get(id - self.list.first_id), Some(Some(_))))
+            .unwrap_or(self.end_id);
+    }
+}
+
+/// A read-only cursor over the items of an `ExpirationList` in ascending ID order. Created by
+/// `ExpirationList::cursor`. The cursor starts on a "ghost" position before the first item, and
+/// moving past the last item returns it to the ghost position.
+pub struct Cursor<'a, T, S = BuildHasherDefault<FnvHasher>> {
+    list: &'a ExpirationList<T, S>,
+    map_ids: Vec<usize>,
+    current_id: Option<usize>,
+}
+
+impl<'a, T, S: BuildHasher> Cursor<'a, T, S> {
+    /// Returns the item that the cursor is on, or `None` on the ghost position.
+    pub fn current(&self) -> Option<(usize, &'a T)> {
+        let id = self.current_id?;
+        return Some((id, self.list.get(id)?));
+    }
+
+    /// Moves the cursor to the next item.
+    pub fn move_next(&mut self) {
+        self.current_id = self.id_after(self.current_id);
+    }
+
+    /// Returns the item after the one that the cursor is on without moving the cursor.
+    pub fn peek_next(&self) -> Option<(usize, &'a T)> {
+        let id = self.id_after(self.current_id)?;
+        return Some((id, self.list.get(id)?));
+    }
+
+    /// Returns the ID of the first item after `id`, or of the first item when `id` is `None`.
+    fn id_after(&self, id: Option<usize>) -> Option<usize> {
+        let start_id = id.map_or(0, |id| id + 1);
+        let map_idx = self.map_ids.partition_point(|map_id| *map_id < start_id);
+        if let Some(map_id) = self.map_ids.get(map_idx) {
+            return Some(*map_id);
+        }
+        let list = self.list;
+        return (start_id.max(list.first_id)..list.next_id())
+            .find(|id| list.list[id - list.first_id].is_some());
+    }
+}
+
+/// An iterator whose items borrow from the iterator itself, so each item must be released before
+/// the next one is requested.
+pub trait LendingIterator {
+    type Item<'a>
+    where
+        Self: 'a;
+
+    fn next(&mut self) -> Option<Self::Item<'_>>;
+}
+
+/// Lends out mutable references to the items of an `ExpirationList` one at a time in ascending
+/// ID order. Created by `ExpirationList::lending_iter_mut`.
+pub struct ExpirationListLendingIter<'a, T, S = BuildHasherDefault<FnvHasher>> {
+    list: &'a mut ExpirationList<T, S>,
+    map_ids: Vec<usize>,
+    map_pos: usize,
+    list_id: usize,
+}
+
+impl<T, S: BuildHasher> LendingIterator for ExpirationListLendingIter<'_, T, S> {
+    type Item<'b>
+        = (usize, &'b mut T)
+    where
+        Self: 'b;
+
+    fn next(&mut self) -> Option<Self::Item<'_>> {
+        if let Some(id) = self.map_ids.get(self.map_pos).copied() {
+            self.map_pos += 1;
+            return Some((id, self.list.map.get_mut(&id)?));
+        }
+        let first_id = self.list.first_id;
+        let idx = (self.list_id - first_id..self.list.list.len())
+            .find(|idx| self.list.list[*idx].is_some())?;
+        self.list_id = first_id + idx + 1;
+        return Some((first_id + idx, self.list.list[idx].as_mut()?));
+    }
+}
+
+/// Iterates over clones of the items of a shared `ExpirationList` in ascending ID order while
+/// keeping the list alive through an `Arc`. Created by `ExpirationList::snapshot_iter`.
+pub struct ExpirationListSnapshotIter<T, S = BuildHasherDefault<FnvHasher>> {
+    list: Arc<ExpirationList<T, S>>,
+    map_ids: Vec<usize>,
+    map_pos: usize,
+    list_idx: usize,
+}
+
+impl<T: Clone, S: BuildHasher> Iterator for ExpirationListSnapshotIter<T, S> {
+    type Item = (usize, T);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(id) = self.map_ids.get(self.map_pos).copied() {
+            self.map_pos += 1;
+            return Some((id, self.list.map.get(&id)?.clone()));
+        }
+        while let Some(value) = self.list.list.get(self.list_idx) {
+            self.list_idx += 1;
+            if let Some(value) = value {
+                return Some((self.list.first_id + self.list_idx - 1, value.clone()));
+            }
+        }
+        return None;
+    }
 }
 
 impl<'a, T> Iterator for ExpirationListIter<'a, T> {
@@ -108,6 +539,7 @@ impl<'a, T> Iterator for ExpirationListIter<'a, T> {
             match &mut self.map_iter {
                 Some(map_iter) => {
                     if let Some((key, value)) = map_iter.next() {
+                        self.remaining -= 1;
                         return Some((*key, value));
                     } else {
                         self.map_iter = None; // Continue to list iter
@@ -117,7 +549,10 @@ impl<'a, T> Iterator for ExpirationListIter<'a, T> {
                     let next = self.list_iter.next();
                     self.list_id += 1;
                     match next {
-                        Some(Some(value)) => return Some((self.list_id - 1, value)),
+                        Some(Some(value)) => {
+                            self.remaining -= 1;
+                            return Some((self.list_id - 1, value));
+                        }
                         Some(None) => (), // Continue to next item in the list
                         None => return None,
                     }
@@ -125,6 +560,10 @@ impl<'a, T> Iterator for ExpirationListIter<'a, T> {
             }
         }
     }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
 }
 impl<'a, T> Iterator for ExpirationListIterMut<'a, T> {
     type Item = (usize, &'a mut T);
@@ -134,6 +573,7 @@ impl<'a, T> Iterator for ExpirationListIterMut<'a, T> {
             match &mut self.map_iter {
                 Some(map_iter) => {
                     if let Some((key, value)) = map_iter.next() {
+                        self.remaining -= 1;
                         return Some((*key, value));
                     } else {
                         self.map_iter = None; // Continue to list iter
@@ -143,7 +583,45 @@ impl<'a, T> Iterator for ExpirationListIterMut<'a, T> {
                     let next = self.list_iter.next();
                     self.list_id += 1;
                     match next {
-                        Some(Some(value)) => return Some((self.list_id - 1, value)),
+                        Some(Some(value)) => {
+                            self.remaining -= 1;
+                            return Some((self.list_id - 1, value));
+                        }
+                        Some(None) => (), // Continue to next item in the list
+                        None => return None,
+                    }
+                }
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<T> Iterator for ExpirationListIntoIter<T> {
+    type Item = (usize, T);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match &mut self.map_iter {
+                Some(map_iter) => {
+                    if let Some((key, value)) = map_iter.next() {
+                        self.remaining -= 1;
+                        return Some((key, value));
+                    } else {
+                        self.map_iter = None; // Continue to list iter
+                    }
+                }
+                None => {
+                    let next = self.list_iter.next();
+                    self.list_id += 1;
+                    match next {
+                        Some(Some(value)) => {
+                            self.remaining -= 1;
+                            return Some((self.list_id - 1, value));
+                        }
                         Some(None) => (), // Continue to next item in the list
                         None => return None,
                     }
@@ -151,26 +629,86 @@ impl<'a, T> Iterator for ExpirationListIterMut<'a, T> {
             }
         }
     }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<T> Iterator for ExpirationListDrain<'_, T> {
+    type Item = (usize, T);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(item) = self.map_iter.next() {
+            return Some(item);
+        }
+        for value in self.list_iter.by_ref() {
+            self.list_id += 1;
+            if let Some(value) = value {
+                return Some((self.list_id - 1, value));
+            }
+        }
+        return None;
+    }
+}
+
+impl<'a, T, S: BuildHasher> Iterator for ExpirationListStrictIter<'a, T, S> {
+    type Item = (usize, &'a T);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (id, value) = self.iter.next()?;
+            // The map is iterated first, so the item came from the map if it is not exhausted yet
+            let from_map = self.iter.map_iter.is_some();
+            if from_map && id >= self.list.first_id && self.list.get(id).is_some() {
+                self.anomalies += 1;
+                continue;
+            }
+            return Some((id, value));
+        }
+    }
+}
+
+impl<'a, T, S: BuildHasher> Iterator for ExpirationListRangeIter<'a, T, S> {
+    type Item = (usize, &'a T);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let list = self.list;
+        if let Some(id) = self.map_ids.get(self.map_pos) {
+            self.map_pos += 1;
+            return Some((*id, &list.map[id]));
+        }
+        while self.list_id < self.end_id {
+            let id = self.list_id;
+            self.list_id += 1;
+            if let Some(Some(value)) = list.list.get(id - list.first_id) {
+                return Some((id, value));
+            }
+        }
+        return None;
+    }
 }
 
-impl<'a, T> IntoIterator for &'a ExpirationList<T> {
+impl<'a, T, S> IntoIterator for &'a ExpirationList<T, S> {
     type Item = (usize, &'a T);
     type IntoIter = ExpirationListIter<'a, T>;
 
     fn into_iter(self) -> Self::IntoIter {
         ExpirationListIter {
+            remaining: self.count + self.map.len(),
             list_iter: self.list.iter(),
             map_iter: Some(self.map.iter()),
             list_id: self.first_id,
         }
     }
 }
-impl<'a, T> IntoIterator for &'a mut ExpirationList<T> {
+impl<'a, T, S> IntoIterator for &'a mut ExpirationList<T, S> {
     type Item = (usize, &'a mut T);
     type IntoIter = ExpirationListIterMut<'a, T>;
 
     fn into_iter(self) -> Self::IntoIter {
         ExpirationListIterMut {
+            remaining: self.count + self.map.len(),
             list_iter: self.list.iter_mut(),
             map_iter: Some(self.map.iter_mut()),
             list_id: self.first_id,
@@ -178,205 +716,4543 @@ impl<'a, T> IntoIterator for &'a mut ExpirationList<T> {
     }
 }
 
-impl<T> Default for ExpirationList<T> {
-    fn default() -> Self {
-        ExpirationList {
-            first_id: 0,
-            count: 0,
-            list: Vec::new(),
-            map: FnvHashMap::default(),
+impl<T, S> IntoIterator for ExpirationList<T, S> {
+    type Item = (usize, T);
+    type IntoIter = ExpirationListIntoIter<T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        ExpirationListIntoIter {
+            remaining: self.count + self.map.len(),
+            list_iter: self.list.into_iter(),
+            map_iter: Some(self.map.into_iter()),
+            list_id: self.first_id,
         }
     }
 }
 
-impl<T> ExpirationList<T> {
-    pub fn new() -> Self {
-        Default::default()
-    }
-
-    /// Adds a new item to the `ExpirationList` and returns its stable ID.
-    pub fn add(&mut self, value: T) -> usize {
-        self.list.push(Some(value));
-        self.count += 1;
-        return self.first_id + self.list.len() - 1;
-    }
-
-    /// Removes an item by ID and returns `Some(item: T)` when the item was found and `None` when
-    /// the item was not found.
-    pub fn remove(&mut self, id: usize) -> Option<T> {
-        if id < self.first_id {
-            return self.map.remove(&id);
-        }
+impl<T> ExactSizeIterator for ExpirationListIter<'_, T> {}
+impl<T> ExactSizeIterator for ExpirationListIterMut<'_, T> {}
+impl<T> ExactSizeIterator for ExpirationListIntoIter<T> {}
 
-        let mut removed_value = None;
-        std::mem::swap(self.list.get_mut(id - self.first_id)?, &mut removed_value);
-        if removed_value.is_none() {
-            return None;
-        }
-        self.count -= 1;
+// The map iterator is dropped once it runs out and the list iterators are fused themselves.
+impl<T> FusedIterator for ExpirationListIter<'_, T> {}
+impl<T> FusedIterator for ExpirationListIterMut<'_, T> {}
+impl<T> FusedIterator for ExpirationListIntoIter<T> {}
 
-        let original_len = self.list.len();
-        if self.count * 2 < original_len && original_len > 32 {
-            let mut shrink_count = self.list.len() / 2;
-            let mut swap_list = Vec::with_capacity(original_len);
-            std::mem::swap(&mut self.list, &mut swap_list);
-            swap_list
-                .into_iter()
-                .enumerate()
-                .for_each(|(idx, value): (usize, Option<T>)| {
-                    if idx < shrink_count {
-                        if let Some(inner_value) = value {
-                            self.map.insert(idx + self.first_id, inner_value);
-                            self.count -= 1;
-                        }
-                        // If the number of non-None items is still less than half the remaining length
-                        // then keep shrinking.
-                        let remaining_len = original_len - shrink_count;
-                        if idx == shrink_count - 1
-                            && self.count * 2 < remaining_len
-                            && remaining_len > 32
-                        {
-                            shrink_count += remaining_len / 2;
-                        }
-                    } else {
-                        self.list.push(value);
-                    }
-                });
-            self.first_id += shrink_count;
-        }
+/// Looks up an item by ID like `ExpirationList::get`, panicking when there is no item with the ID.
+impl<T, S: BuildHasher> Index<usize> for ExpirationList<T, S> {
+    type Output = T;
 
-        return removed_value;
+    fn index(&self, id: usize) -> &T {
+        return self
+            .get(id)
+            .unwrap_or_else(|| panic!("no item with id {id}"));
     }
+}
 
-    /// Takes an item ID and returns `Some(item: &T)` when the item is found and `None` otherwise.
-    pub fn get(&self, id: usize) -> Option<&T> {
-        if id < self.first_id {
-            return self.map.get(&id);
-        }
-        return self.list.get(id - self.first_id)?.as_ref();
+/// Looks up an item by ID like `ExpirationList::get_mut`, panicking when there is no item with the
+/// ID.
+impl<T, S: BuildHasher> IndexMut<usize> for ExpirationList<T, S> {
+    fn index_mut(&mut self, id: usize) -> &mut T {
+        return self
+            .get_mut(id)
+            .unwrap_or_else(|| panic!("no item with id {id}"));
     }
+}
 
-    /// Takes an item ID and returns `Some(item: &mut T)` when the item is found and `None`
-    /// otherwise.
-    pub fn get_mut(&mut self, id: usize) -> Option<&mut T> {
-        if id < self.first_id {
-            return self.map.get_mut(&id);
+/// Stores each value under its ID like `ExpirationList::set`. When an ID appears more than once,
+/// the last value wins.
+impl<T, S: BuildHasher> Extend<(usize, T)> for ExpirationList<T, S> {
+    fn extend<I: IntoIterator<Item = (usize, T)>>(&mut self, iter: I) {
+        for (id, value) in iter {
+            self.set(id, value);
         }
-        return self.list.get_mut(id - self.first_id)?.as_mut();
     }
+}
 
-    /// Returns `true` if an item with the given ID exists. Returns `false` otherwise.
-    pub fn contains(&self, id: usize) -> bool {
-        if id < self.first_id {
-            return self.map.contains_key(&id);
-        } else if let Some(Some(_)) = self.list.get(id) {
-            return true;
-        }
-        return false;
+/// Adds every item like `add_many`, continuing the IDs from where the list left off.
+impl<T, S: BuildHasher> Extend<T> for ExpirationList<T, S> {
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+        self.add_many(iter);
     }
+}
 
-    pub fn len(&self) -> usize {
-        self.count + self.map.len()
+/// Collects the items with IDs from 0 in iteration order, the same IDs that calling `add` for
+/// each of them would give.
+impl<T, S: BuildHasher + Default> FromIterator<T> for ExpirationList<T, S> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        let mut list = Self::default();
+        list.add_many(iter);
+        return list;
     }
+}
 
-    pub fn capacity(&self) -> usize {
-        self.list.capacity() + self.map.capacity()
+impl<T, S: BuildHasher + Default> Default for ExpirationList<T, S> {
+    fn default() -> Self {
+        Self::with_hasher(S::default())
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// Clones the items with their IDs and every setting. Callbacks registered with `on_grow` or
+/// `with_spill` cannot be cloned, so the clone has none.
+impl<T: Clone, S: Clone> Clone for ExpirationList<T, S> {
+    fn clone(&self) -> Self {
+        ExpirationList {
+            first_id: self.first_id,
+            count: self.count,
+            list: self.list.clone(),
+            map: self.map.clone(),
+            base_id: self.base_id,
+            free_slots: self.free_slots.clone(),
+            map_ratio_cap: self.map_ratio_cap,
+            hysteresis: self.hysteresis,
+            shrink_policy: self.shrink_policy,
+            in_place_shrink: self.in_place_shrink,
+            auto_compact: self.auto_compact,
+            slow_hits: AtomicUsize::new(self.slow_hits.load(AtomicOrdering::Relaxed)),
+            shrink_armed: self.shrink_armed,
+            last_shrink_len: self.last_shrink_len,
+            shrink_visits: self.shrink_visits,
+            removals: self.removals,
+            pinned: self.pinned.clone(),
+            grow_hook: None,
+            spill_hook: None,
+        }
+    }
+}
+
+/// Compares the items and their IDs, regardless of whether they are stored in the `Vec` or the
+/// `HashMap`. Settings such as the free list are not compared.
+impl<T: PartialEq, S: BuildHasher> PartialEq for ExpirationList<T, S> {
+    fn eq(&self, other: &Self) -> bool {
+        return self.len() == other.len()
+            && self
+                .into_iter()
+                .all(|(id, value)| other.get(id) == Some(value));
+    }
+}
+
+impl<T: Eq, S: BuildHasher> Eq for ExpirationList<T, S> {}
+
+/// The serialized form of an `ExpirationList`: the live items in ascending ID order, the ID the
+/// next item will get and the settings. Empty slots and the split between the `Vec` and the
+/// `HashMap` are not part of the format.
+#[cfg(feature = "serde_support")]
+#[derive(serde::Serialize)]
+#[serde(bound(serialize = "T: serde::Serialize, S: BuildHasher"))]
+struct SerializedList<'a, T, S> {
+    next_id: usize,
+    count: usize,
+    items: SerializedItems<'a, T, S>,
+    base_id: usize,
+    free_list: bool,
+    map_ratio_cap: Option<f64>,
+    hysteresis: Option<(f64, f64)>,
+    shrink_policy: ShrinkPolicy,
+    in_place_shrink: bool,
+    auto_compact: Option<usize>,
+}
+
+#[cfg(feature = "serde_support")]
+struct SerializedItems<'a, T, S>(&'a ExpirationList<T, S>);
+
+#[cfg(feature = "serde_support")]
+impl<T: serde::Serialize, S: BuildHasher> serde::Serialize for SerializedItems<'_, T, S> {
+    fn serialize<Z: serde::Serializer>(&self, serializer: Z) -> Result<Z::Ok, Z::Error> {
+        return serializer.collect_seq(self.0.iter_range(..));
+    }
+}
+
+/// Accepts the serialized form above as well as the older layout written by the derive, which
+/// stored `first_id`, `list`, `map` and `free_slots` directly.
+#[cfg(feature = "serde_support")]
+#[derive(serde::Deserialize)]
+#[serde(bound(deserialize = "T: serde::Deserialize<'de>, S: BuildHasher + Default"))]
+struct DeserializedList<T, S> {
+    #[serde(default)]
+    next_id: usize,
+    #[serde(default)]
+    count: usize,
+    #[serde(default)]
+    items: Option<Vec<(usize, T)>>,
+    #[serde(default)]
+    first_id: usize,
+    #[serde(default)]
+    list: Vec<Option<T>>,
+    #[serde(default)]
+    map: HashMap<usize, T, S>,
+    #[serde(default)]
+    free_slots: Option<BinaryHeap<Reverse<usize>>>,
+    #[serde(default)]
+    base_id: usize,
+    #[serde(default)]
+    free_list: bool,
+    #[serde(default)]
+    map_ratio_cap: Option<f64>,
+    #[serde(default)]
+    hysteresis: Option<(f64, f64)>,
+    #[serde(default)]
+    shrink_policy: ShrinkPolicy,
+    #[serde(default)]
+    in_place_shrink: bool,
+    #[serde(default)]
+    auto_compact: Option<usize>,
+}
+
+#[cfg(feature = "serde_support")]
+impl<T, S: BuildHasher + Default> DeserializedList<T, S> {
+    /// Rebuilds an `ExpirationList`. The densest suffix of the items that would not be shrunk right
+    /// away goes in the `Vec` and the older items go in the `HashMap`.
+    fn into_list(self) -> Result<ExpirationList<T, S>, String> {
+        let mut list = ExpirationList::from_map(HashMap::with_hasher(S::default()));
+        list.base_id = self.base_id;
+        list.map_ratio_cap = self.map_ratio_cap;
+        list.hysteresis = self.hysteresis;
+        list.shrink_policy = self.shrink_policy;
+        list.in_place_shrink = self.in_place_shrink;
+        list.auto_compact = self.auto_compact;
+
+        let Some(mut items) = self.items else {
+            list.first_id = self.first_id;
+            list.count = self.list.iter().flatten().count();
+            list.list = self.list;
+            list.map = self.map;
+            list.free_slots = self.free_slots;
+            return Ok(list);
+        };
+        if items.len() != self.count {
+            return Err(format!(
+                "expected {} items, found {}",
+                self.count,
+                items.len()
+            ));
+        }
+        items.sort_unstable_by_key(|(id, _)| *id);
+        for pair in items.windows(2) {
+            if pair[0].0 == pair[1].0 {
+                return Err(format!("duplicate item with id {}", pair[0].0));
+            }
+        }
+        if let Some((id, _)) = items.last()
+            && *id >= self.next_id
+        {
+            return Err(format!(
+                "item with id {id} is not below next_id {}",
+                self.next_id
+            ));
+        }
+
+        let policy = list.sparse_policy();
+        let mut split = items.len();
+        for idx in (0..items.len()).rev() {
+            let len = self.next_id - items[idx].0;
+            if !ExpirationList::<T, S>::is_sparse(policy, items.len() - idx, len) {
+                split = idx;
+            }
+        }
+        list.first_id = items.get(split).map_or(self.next_id, |(id, _)| *id);
+        list.list.resize_with(self.next_id - list.first_id, || None);
+        list.count = items.len() - split;
+        for (id, value) in items.drain(split..) {
+            list.list[id - list.first_id] = Some(value);
+        }
+        list.map.extend(items);
+        if self.free_list {
+            let first_id = list.first_id;
+            list.free_slots = Some(
+                (list.list.iter().enumerate())
+                    .filter(|(_, value)| value.is_none())
+                    .map(|(idx, _)| Reverse(idx + first_id))
+                    .collect(),
+            );
+        }
+        return Ok(list);
+    }
+}
+
+/// Serializes only the live items, so the size tracks `len()` rather than the capacity.
+#[cfg(feature = "serde_support")]
+impl<T: serde::Serialize, S: BuildHasher> serde::Serialize for ExpirationList<T, S> {
+    fn serialize<Z: serde::Serializer>(&self, serializer: Z) -> Result<Z::Ok, Z::Error> {
+        return SerializedList {
+            next_id: self.next_id(),
+            count: self.len(),
+            items: SerializedItems(self),
+            base_id: self.base_id,
+            free_list: self.free_slots.is_some(),
+            map_ratio_cap: self.map_ratio_cap,
+            hysteresis: self.hysteresis,
+            shrink_policy: self.shrink_policy,
+            in_place_shrink: self.in_place_shrink,
+            auto_compact: self.auto_compact,
+        }
+        .serialize(serializer);
+    }
+}
+
+#[cfg(feature = "serde_support")]
+impl<'de, T: serde::Deserialize<'de>, S: BuildHasher + Default> serde::Deserialize<'de>
+    for ExpirationList<T, S>
+{
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let list = DeserializedList::deserialize(deserializer)?
+            .into_list()
+            .map_err(serde::de::Error::custom)?;
+        list.check_invariants();
+        return Ok(list);
+    }
+}
+
+impl<T> ExpirationList<T> {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Creates an `ExpirationList` with space for `capacity` items in the `Vec`, so that adding
+    /// that many items does not reallocate.
+    pub fn with_capacity(capacity: usize) -> Self {
+        return Self::with_capacity_and_first_id(capacity, 0);
+    }
+
+    /// Creates an `ExpirationList` with space for `capacity` items in the `Vec` whose first item
+    /// gets the ID `base`. This is useful when several lists share one ID space, such as shards.
+    pub fn with_capacity_and_first_id(capacity: usize, base: usize) -> Self {
+        ExpirationList {
+            first_id: base,
+            base_id: base,
+            list: Vec::with_capacity(capacity),
+            ..Default::default()
+        }
+    }
+
+    /// Estimates the most slots the `Vec` of an `ExpirationList` uses while it holds about `n`
+    /// items, for use as its initial capacity. Removed items leave empty slots behind until the
+    /// next shrink, and a shrink only happens once more than half of the slots are empty, so the
+    /// estimate is about twice `n`. Lists with hysteresis or a `ShrinkPolicy` may need more,
+    /// depending on their low watermark or fill ratio.
+    pub fn capacity_for(n: usize) -> usize {
+        return n.saturating_mul(2).saturating_add(1).max(33);
+    }
+
+    /// Creates an `ExpirationList` with `n` items where the item with ID `i` is `f(i)`.
+    pub fn from_fn(n: usize, f: impl FnMut(usize) -> T) -> Self {
+        ExpirationList {
+            count: n,
+            list: (0..n).map(f).map(Some).collect(),
+            ..Default::default()
+        }
+    }
+
+    /// Creates an `ExpirationList` directly from its inner parts: the ID of the first slot in
+    /// `list` and the `HashMap` of items with lower IDs. The parts are not checked for
+    /// consistency, so IDs in `map` at or above `first_id` are not reachable through `get`.
+    pub fn from_parts(
+        first_id: usize,
+        list: Vec<Option<T>>,
+        map: HashMap<usize, T, BuildHasherDefault<FnvHasher>>,
+    ) -> Self {
+        ExpirationList {
+            first_id,
+            count: list.iter().filter(|value| value.is_some()).count(),
+            list,
+            ..Self::from_map(map)
+        }
+    }
+
+    /// Creates an `ExpirationList` whose shrink never lets the inner `HashMap` hold more than
+    /// `cap` times as many items as the `Vec` has slots. When a shrink reaches the cap it stops
+    /// early, and if moving every item back into the `Vec` with `compact` would leave it dense,
+    /// that is done instead. A small cap keeps lookups fast at the cost of a longer `Vec`.
+    pub fn with_map_ratio_cap(cap: f64) -> Self {
+        ExpirationList {
+            map_ratio_cap: Some(cap),
+            ..Default::default()
+        }
+    }
+
+    /// Creates an `ExpirationList` that shrinks when less than `low` of the slots in the `Vec` are
+    /// used, rather than half of them. After a shrink, the next one waits until more than `high`
+    /// of the slots are used again or the `Vec` has doubled in length. This keeps workloads that
+    /// hover around the threshold from shrinking on every removal.
+    ///
+    /// Panics unless `0.0 <= low <= high <= 1.0`.
+    pub fn with_hysteresis(low: f64, high: f64) -> Self {
+        return Self::with_config(Config {
+            hysteresis: Some((low, high)),
+            ..Default::default()
+        });
+    }
+
+    /// Creates an `ExpirationList` that shrinks according to `policy` instead of the default of
+    /// shrinking `Vec`s longer than 32 slots once they are less than half full.
+    ///
+    /// Panics unless `0.0 <= policy.fill_ratio <= 1.0`.
+    pub fn with_policy(policy: ShrinkPolicy) -> Self {
+        return Self::with_config(Config {
+            shrink_policy: policy,
+            ..Default::default()
+        });
+    }
+
+    /// Creates an `ExpirationList` with every setting in `config`, in place of combining the
+    /// `with_*` constructors.
+    ///
+    /// Panics if the hysteresis watermarks or the fill ratio are invalid, like `with_hysteresis`
+    /// and `with_policy`.
+    pub fn with_config(config: Config) -> Self {
+        if let Some((low, high)) = config.hysteresis {
+            assert!(
+                0.0 <= low && low <= high && high <= 1.0,
+                "hysteresis watermarks must satisfy 0.0 <= low <= high <= 1.0, got {low} and {high}"
+            );
+        }
+        let fill_ratio = config.shrink_policy.fill_ratio;
+        assert!(
+            (0.0..=1.0).contains(&fill_ratio),
+            "shrink fill ratio must satisfy 0.0 <= fill_ratio <= 1.0, got {fill_ratio}"
+        );
+        ExpirationList {
+            shrink_policy: config.shrink_policy,
+            hysteresis: config.hysteresis,
+            map_ratio_cap: config.map_ratio_cap,
+            in_place_shrink: config.in_place_shrink,
+            auto_compact: config.auto_compact,
+            free_slots: config.free_list.then(BinaryHeap::new),
+            ..Default::default()
+        }
+    }
+
+    /// Creates an `ExpirationList` that shrinks the `Vec` in place. A normal shrink copies the
+    /// remaining slots into a new `Vec`, so for a moment both the old and the new one are
+    /// allocated. Shrinking in place moves the slots to the front of the existing `Vec` instead,
+    /// which keeps the peak memory down when `T` is large, but the `Vec` keeps its capacity until
+    /// `shrink_to_fit` is called.
+    pub fn with_in_place_shrink() -> Self {
+        ExpirationList {
+            in_place_shrink: true,
+            ..Default::default()
+        }
+    }
+
+    /// Creates an `ExpirationList` that compacts itself once `threshold` lookups in a row have
+    /// found their item in the inner `HashMap`, since heavy use of old items suggests moving them
+    /// back into the `Vec` would pay off. Lookups through `get` are counted too, but only
+    /// `get_mut` and `add` can compact. The compaction is skipped if it would leave the `Vec`
+    /// mostly empty, in which case counting starts over.
+    pub fn with_auto_compact(threshold: usize) -> Self {
+        ExpirationList {
+            auto_compact: Some(threshold),
+            ..Default::default()
+        }
+    }
+
+    /// Creates an `ExpirationList` that hands every item a shrink would move into the inner
+    /// `HashMap` to `spill` instead, together with its ID, for example to write it to slower
+    /// storage. The `HashMap` stays empty, and `get` returns `None` for spilled IDs.
+    /// The callback must be unwind safe so that the `ExpirationList` stays `UnwindSafe`.
+    pub fn with_spill(
+        spill: impl FnMut(usize, T) + Send + Sync + UnwindSafe + RefUnwindSafe + 'static,
+    ) -> Self {
+        ExpirationList {
+            spill_hook: Some(Hook(Box::new(spill))),
+            ..Default::default()
+        }
+    }
+
+    /// Creates an `ExpirationList` that reuses the IDs of removed items. When an item is removed
+    /// from the `Vec` its slot is recorded, and the next `add` fills the lowest recorded slot
+    /// instead of appending. This keeps the `Vec` from growing under heavy add/remove churn, but
+    /// IDs are no longer unique over the lifetime of the `ExpirationList`: an ID that was removed
+    /// may later refer to a different item.
+    pub fn with_free_list() -> Self {
+        ExpirationList {
+            free_slots: Some(BinaryHeap::new()),
+            ..Default::default()
+        }
+    }
+}
+
+impl<T, S: BuildHasher> ExpirationList<T, S> {
+    /// Creates an `ExpirationList` whose inner `HashMap` uses `hasher` instead of FNV, for
+    /// example `RandomState` when IDs may be chosen by an attacker.
+    pub fn with_hasher(hasher: S) -> Self {
+        return Self::from_map(HashMap::with_hasher(hasher));
+    }
+
+    /// Returns the settings of the `ExpirationList`, which `with_config` accepts to create another
+    /// one that behaves the same.
+    pub fn config(&self) -> Config {
+        return Config {
+            shrink_policy: self.shrink_policy,
+            hysteresis: self.hysteresis,
+            map_ratio_cap: self.map_ratio_cap,
+            in_place_shrink: self.in_place_shrink,
+            auto_compact: self.auto_compact,
+            free_list: self.free_slots.is_some(),
+        };
+    }
+
+    /// Creates an empty `ExpirationList` around an inner `HashMap`.
+    fn from_map(map: HashMap<usize, T, S>) -> Self {
+        ExpirationList {
+            first_id: 0,
+            count: 0,
+            list: Vec::new(),
+            map,
+            base_id: 0,
+            free_slots: None,
+            map_ratio_cap: None,
+            hysteresis: None,
+            shrink_policy: ShrinkPolicy::default(),
+            in_place_shrink: false,
+            auto_compact: None,
+            slow_hits: AtomicUsize::new(0),
+            shrink_armed: true,
+            last_shrink_len: 0,
+            shrink_visits: 0,
+            removals: 0,
+            pinned: Vec::new(),
+            grow_hook: None,
+            spill_hook: None,
+        }
+    }
+
+    /// Adds a new item to the `ExpirationList` and returns its stable ID.
+    ///
+    /// Panics if every ID up to `usize::MAX` has been handed out, see `try_add`.
+    pub fn add(&mut self, value: T) -> usize {
+        return match self.try_add(value) {
+            Ok(id) => id,
+            Err(error) => panic!("{error}"),
+        };
+    }
+
+    /// Adds a new item like `add`, but returns an error instead of panicking when every ID up to
+    /// `usize::MAX` has been handed out. `next_id` shows how many IDs are left.
+    pub fn try_add(&mut self, value: T) -> Result<usize, CapacityError> {
+        self.auto_compact_now();
+        if let Some(id) = self.pop_free_slot() {
+            self.list[id - self.first_id] = Some(value);
+            self.count += 1;
+            self.rearm_shrink();
+            self.check_invariants();
+            return Ok(id);
+        }
+        let id = self.next_id();
+        if id == usize::MAX {
+            return Err(CapacityError);
+        }
+        self.push_slot(Some(value));
+        self.count += 1;
+        self.rearm_shrink();
+        self.check_invariants();
+        return Ok(id);
+    }
+
+    /// Reserves space for at least `additional` more items in the inner `Vec`, for example ahead
+    /// of a burst of `add` calls. Does nothing if the capacity is already sufficient.
+    pub fn reserve(&mut self, additional: usize) {
+        self.list.reserve(additional);
+    }
+
+    /// Reserves space for exactly `additional` more items in the inner `Vec`.
+    pub fn reserve_exact(&mut self, additional: usize) {
+        self.list.reserve_exact(additional);
+    }
+
+    /// Tries to reserve space for exactly `additional` more items in the inner `Vec`.
+    pub fn try_reserve_exact(&mut self, additional: usize) -> Result<(), TryReserveError> {
+        return self.list.try_reserve_exact(additional);
+    }
+
+    /// Adds every item from `items` in one pass and returns the contiguous range of IDs they were
+    /// given. Space is reserved up front from the iterator's size hint. The items always get new
+    /// IDs, even when the free list is enabled.
+    ///
+    /// Panics if the IDs run out, like `add`.
+    pub fn add_many<I: IntoIterator<Item = T>>(&mut self, items: I) -> Range<usize> {
+        self.auto_compact_now();
+        let items = items.into_iter();
+        let start_id = self.next_id();
+        self.list.reserve(items.size_hint().0);
+        for value in items {
+            self.push_slot(Some(value));
+            self.count += 1;
+        }
+        self.rearm_shrink();
+        self.check_invariants();
+        return start_id..self.next_id();
+    }
+
+    /// Adds every item from `items` and returns the range of IDs they were given. Space is
+    /// reserved up front from the iterator's size hint, and if the inner `Vec` cannot grow the
+    /// error reports how many items were already added. The items always get new IDs, even when
+    /// the free list is enabled.
+    pub fn try_add_iter(
+        &mut self,
+        items: impl IntoIterator<Item = T>,
+    ) -> Result<Range<usize>, TryAddError> {
+        let items = items.into_iter();
+        let start_id = self.next_id();
+        self.list
+            .try_reserve(items.size_hint().0)
+            .map_err(|error| TryAddError {
+                added: 0,
+                error: TryAddCause::Alloc(error),
+            })?;
+        let mut failure = None;
+        for (added, value) in items.enumerate() {
+            if self.next_id() == usize::MAX {
+                failure = Some(TryAddError {
+                    added,
+                    error: TryAddCause::Capacity(CapacityError),
+                });
+                break;
+            }
+            let reserved = if self.list.len() < self.list.capacity() {
+                Ok(())
+            } else {
+                self.list.try_reserve(1)
+            };
+            if let Err(error) = reserved {
+                failure = Some(TryAddError {
+                    added,
+                    error: TryAddCause::Alloc(error),
+                });
+                break;
+            }
+            self.push_slot(Some(value));
+            self.count += 1;
+        }
+        self.rearm_shrink();
+        self.check_invariants();
+        if let Some(error) = failure {
+            return Err(error);
+        }
+        return Ok(start_id..self.next_id());
+    }
+
+    /// Adds a clone of every item in `values` and returns the range of IDs they were given. The
+    /// items always get new IDs, even when the free list is enabled.
+    ///
+    /// Panics if the IDs run out, like `add`.
+    pub fn append_from_slice(&mut self, values: &[T]) -> Range<usize>
+    where
+        T: Clone,
+    {
+        let start_id = self.next_id();
+        self.list.reserve(values.len());
+        for value in values {
+            self.push_slot(Some(value.clone()));
+        }
+        self.count += values.len();
+        self.rearm_shrink();
+        self.check_invariants();
+        return start_id..self.next_id();
+    }
+
+    /// Adds items created by `f` until the `ExpirationList` holds `new_total` items. The new items
+    /// get consecutive IDs. Does nothing if there are already `new_total` or more items.
+    ///
+    /// Panics if the IDs run out, like `add`.
+    pub fn resize_with(&mut self, new_total: usize, mut f: impl FnMut() -> T) {
+        let additional = new_total.saturating_sub(self.len());
+        self.list.reserve(additional);
+        for _ in 0..additional {
+            self.push_slot(Some(f()));
+            self.count += 1;
+        }
+        self.rearm_shrink();
+        self.check_invariants();
+    }
+
+    /// Appends a slot to the inner `Vec`, notifying the `on_grow` callback if the `Vec` had to
+    /// reallocate. Panics if the slot would take the last ID, so `next_id` never wraps around.
+    fn push_slot(&mut self, value: Option<T>) {
+        if self.next_id() == usize::MAX {
+            panic!("{CapacityError}");
+        }
+        let old_capacity = self.list.capacity();
+        self.list.push(value);
+        if let Some(Hook(grow_hook)) = &self.grow_hook {
+            let new_capacity = self.list.capacity();
+            if new_capacity != old_capacity {
+                grow_hook(old_capacity, new_capacity);
+            }
+        }
+    }
+
+    /// Registers a callback that is called with `(old_capacity, new_capacity)` whenever adding an
+    /// item makes the inner `Vec` reallocate. This replaces any previously registered callback.
+    /// The callback must be unwind safe so that the `ExpirationList` stays `UnwindSafe`.
+    pub fn on_grow(
+        &mut self,
+        f: impl Fn(usize, usize) + Send + Sync + UnwindSafe + RefUnwindSafe + 'static,
+    ) {
+        self.grow_hook = Some(Hook(Box::new(f)));
+    }
+
+    /// Adds a new item like `add` and returns its stable ID together with whether the inner `Vec`
+    /// had to reallocate to make room for it.
+    pub fn add_tracked(&mut self, value: T) -> (usize, bool) {
+        let old_capacity = self.list.capacity();
+        let id = self.add(value);
+        return (id, self.list.capacity() != old_capacity);
+    }
+
+    /// Adds a new item like `add` and returns its stable ID together with a mutable reference to
+    /// it, so the item can be modified further without a second lookup.
+    pub fn add_entry(&mut self, value: T) -> (usize, &mut T) {
+        let id = self.add(value);
+        let value = self.list[id - self.first_id]
+            .as_mut()
+            .expect("the item was just added");
+        return (id, value);
+    }
+
+    /// Returns the item with the given ID, or adds the item created by `f` when there is none.
+    /// A new item gets the next ID like `add`, which is generally not the requested ID, so the
+    /// ID of the returned item is returned with it.
+    pub fn get_or_add(&mut self, id: usize, f: impl FnOnce() -> T) -> (usize, &mut T) {
+        if self.contains(id) {
+            return (id, self.get_mut(id).expect("the item was just found"));
+        }
+        return self.add_entry(f());
+    }
+
+    /// Stores `value` under `id` whether or not an item with that ID exists, and returns the
+    /// previous item if there was one. IDs below the `Vec` go into the inner `HashMap`, and IDs
+    /// past the end of the `Vec` pad it with empty slots so setting a far away ID allocates a
+    /// slot for every ID in between.
+    pub fn set(&mut self, id: usize, value: T) -> Option<T> {
+        if id < self.first_id {
+            return self.map.insert(id, value);
+        }
+
+        let idx = id - self.first_id;
+        while self.list.len() < idx {
+            let padding_id = self.next_id();
+            if let Some(free_slots) = &mut self.free_slots {
+                free_slots.push(Reverse(padding_id));
+            }
+            self.push_slot(None);
+        }
+        let previous = if idx == self.list.len() {
+            self.push_slot(Some(value));
+            None
+        } else {
+            self.list[idx].replace(value)
+        };
+        if previous.is_none() {
+            self.count += 1;
+        }
+        self.rearm_shrink();
+        self.check_invariants();
+        return previous;
+    }
+
+    /// Removes an item by ID and returns `Some(item: T)` when the item was found and `None` when
+    /// the item was not found.
+    pub fn remove(&mut self, id: usize) -> Option<T> {
+        let removed_value = self.take(id)?;
+        if id >= self.first_id {
+            self.shrink_now();
+        } else {
+            self.shrink_map();
+        }
+        return Some(removed_value);
+    }
+
+    /// Removes the items with each of `ids` and returns how many were removed. Unlike calling
+    /// `remove` for each ID, the `Vec` is only checked for a shrink once at the end. IDs without
+    /// an item, including IDs given more than once, are skipped.
+    pub fn remove_many<I: IntoIterator<Item = usize>>(&mut self, ids: I) -> usize {
+        let mut removed = 0;
+        for id in ids {
+            if self.take(id).is_some() {
+                removed += 1;
+            }
+        }
+        self.shrink_now();
+        self.shrink_map();
+        return removed;
+    }
+
+    /// Keeps only the items for which `f` returns `true`, calling it with the ID and a mutable
+    /// reference to each item. Afterwards the inner `Vec` is shrunk like after a `remove`, so a
+    /// `retain` that drops most items frees their slots.
+    pub fn retain(&mut self, mut f: impl FnMut(usize, &mut T) -> bool) {
+        let map_len = self.map.len();
+        self.map.retain(|id, value| f(*id, value));
+        self.removals += map_len - self.map.len();
+        for (idx, slot) in self.list.iter_mut().enumerate() {
+            let id = idx + self.first_id;
+            if let Some(value) = slot
+                && !f(id, value)
+            {
+                *slot = None;
+                self.count -= 1;
+                self.removals += 1;
+                if let Some(free_slots) = &mut self.free_slots {
+                    free_slots.push(Reverse(id));
+                }
+            }
+        }
+        self.rearm_shrink();
+        self.shrink_now();
+        self.check_invariants();
+    }
+
+    /// Keeps only the items with IDs in `range` for which `pred` returns `true`, like `retain`,
+    /// while items outside of `range` are kept without calling `pred`. Only the part of the `Vec`
+    /// within `range` is visited, and the `HashMap` is skipped when `range` starts in the `Vec`.
+    pub fn retain_in_range(
+        &mut self,
+        range: impl RangeBounds<usize>,
+        mut pred: impl FnMut(usize, &T) -> bool,
+    ) {
+        let (start_id, end_id) = self.id_bounds(range);
+        if start_id < self.first_id {
+            self.map
+                .retain(|id, value| !(start_id..end_id).contains(id) || pred(*id, value));
+        }
+        let start_idx = start_id.max(self.first_id) - self.first_id;
+        let end_idx = end_id.max(self.first_id) - self.first_id;
+        for idx in start_idx..end_idx {
+            let id = idx + self.first_id;
+            if let Some(value) = &self.list[idx]
+                && !pred(id, value)
+            {
+                self.take(id);
+            }
+        }
+        self.shrink_now();
+    }
+
+    /// Removes an item by ID like `remove` and returns it together with whether this removal
+    /// shrank the inner `Vec`, which is the O(n) part of a removal.
+    pub fn remove_profiled(&mut self, id: usize) -> Option<(T, bool)> {
+        let removed_value = self.take(id)?;
+        if id < self.first_id {
+            self.shrink_map();
+            return Some((removed_value, false));
+        }
+        return Some((removed_value, self.shrink_now()));
+    }
+
+    /// Removes an item by ID like `remove` but never shrinks the inner `Vec`. This is useful when
+    /// pulling out many items at once, followed by a single call to `shrink_now`.
+    pub fn take(&mut self, id: usize) -> Option<T> {
+        if id < self.first_id {
+            let removed_value = self.map.remove(&id)?;
+            self.removals += 1;
+            return Some(removed_value);
+        }
+
+        let removed_value = self.list.get_mut(id - self.first_id)?.take()?;
+        self.count -= 1;
+        self.removals += 1;
+        if let Some(free_slots) = &mut self.free_slots {
+            free_slots.push(Reverse(id));
+        }
+        self.rearm_shrink();
+        self.check_invariants();
+        return Some(removed_value);
+    }
+
+    /// Shrinks the inner `Vec` if it holds more than 32 slots and more than half of them are
+    /// empty, or as set by `with_policy`, which `remove` does automatically. Any items in the first
+    /// half that are not yet removed are moved to the inner `HashMap`. Returns whether it shrank,
+    /// which means the lowest ID in the `Vec` changed. With a map ratio cap, a shrink that is
+    /// capped right away may compact instead, which lowers it. Checking whether a shrink is needed
+    /// is O(1), so this is cheap to call when the `Vec` is already dense.
+    pub fn shrink_now(&mut self) -> bool {
+        let original_len = self.list.len();
+        let policy = self.sparse_policy();
+        let limit = self.shrink_limit();
+        if Self::is_sparse(policy, self.count, original_len) && self.shrink_allowed() && limit > 0 {
+            return self.shrink_prefix(policy, limit);
+        }
+        return false;
+    }
+
+    /// Moves the front of the `Vec` into the inner `HashMap` the way `shrink_now` does, without
+    /// checking whether the `Vec` is sparse first. Never goes past `limit`, which must not be 0.
+    /// Returns whether the lowest ID in the `Vec` changed, and leaves the shrink state untouched
+    /// when it did not.
+    fn shrink_prefix(&mut self, policy: ShrinkPolicy, limit: usize) -> bool {
+        let original_len = self.list.len();
+        if self.map_ratio_cap.is_some() || self.in_place_shrink {
+            let first_id = self.first_id;
+            let cap = self.map_ratio_cap.unwrap_or(f64::INFINITY);
+            let capped = self.shrink_within_map_ratio_cap(cap, policy);
+            if capped && self.compacts_densely() {
+                self.compact();
+                self.shrink_visits += self.list.len();
+            }
+            if self.first_id == first_id {
+                return false;
+            }
+            self.finish_shrink();
+            return true;
+        }
+
+        // Always drop at least one slot, so that a single slot can shrink under a floor of 0
+        let mut shrink_count = (self.list.len() / 2).max(1).min(limit);
+        self.shrink_visits += original_len;
+        let mut swap_list = Vec::with_capacity(original_len);
+        core::mem::swap(&mut self.list, &mut swap_list);
+        swap_list
+            .into_iter()
+            .enumerate()
+            .for_each(|(idx, value): (usize, Option<T>)| {
+                if idx < shrink_count {
+                    if let Some(inner_value) = value {
+                        Self::migrate(
+                            &mut self.map,
+                            &mut self.spill_hook,
+                            idx + self.first_id,
+                            inner_value,
+                        );
+                        self.count -= 1;
+                    }
+                    // If the number of non-None items is still less than half the remaining length
+                    // then keep shrinking.
+                    let remaining_len = original_len - shrink_count;
+                    if idx == shrink_count - 1 && Self::is_sparse(policy, self.count, remaining_len)
+                    {
+                        shrink_count = (shrink_count + remaining_len / 2).min(limit);
+                    }
+                } else {
+                    self.list.push(value);
+                }
+            });
+        self.first_id += shrink_count;
+        self.finish_shrink();
+        return true;
+    }
+
+    /// Returns the number of slots visited by shrinks over the lifetime of the `ExpirationList`
+    /// per item removed with `remove`, `take` or `retain`. Removing items oldest first keeps this
+    /// at about 2, while a much higher ratio means the workload makes it shrink over and over, for
+    /// example because `set` pads the `Vec` with empty slots, and a `HashMap` may suit it better.
+    /// Returns 0 before the first removal.
+    pub fn shrink_visit_ratio(&self) -> f64 {
+        if self.removals == 0 {
+            return 0.0;
+        }
+        return self.shrink_visits as f64 / self.removals as f64;
+    }
+
+    /// Once removals leave the inner `HashMap` using less than an eighth of its capacity, moves
+    /// its items back into the `Vec` when that leaves the `Vec` dense, and gives back the spare
+    /// capacity of the `HashMap`. The capacity at least halves between two of these, so they
+    /// cost O(1) per removal on average.
+    fn shrink_map(&mut self) {
+        if self.map.len() * 8 >= self.map.capacity() {
+            return;
+        }
+        if self.compacts_densely() {
+            self.compact();
+        }
+        self.map.shrink_to(self.map.len() * 2);
+    }
+
+    /// Moves an item out of the `Vec` during a shrink, into the inner `HashMap` or to the spill
+    /// callback when there is one.
+    fn migrate(
+        map: &mut HashMap<usize, T, S>,
+        spill_hook: &mut Option<
+            Hook<dyn FnMut(usize, T) + Send + Sync + UnwindSafe + RefUnwindSafe>,
+        >,
+        id: usize,
+        value: T,
+    ) {
+        match spill_hook {
+            Some(Hook(spill)) => spill(id, value),
+            None => {
+                map.insert(id, value);
+            }
+        }
+    }
+
+    /// Frees as much memory as possible. The front of the `Vec` is moved into the inner `HashMap`
+    /// like a shrink would, even when the `Vec` is not sparse, and any empty slots left at its
+    /// front are dropped, which leaves the `Vec` empty when every item in it was removed. Then both
+    /// the `Vec` and the `HashMap` give back their spare capacity.
+    pub fn shrink_to_fit(&mut self) {
+        let limit = self.shrink_limit();
+        if limit > 0 {
+            let policy = self.sparse_policy();
+            self.shrink_prefix(policy, limit);
+        }
+        let leading = self
+            .list
+            .iter()
+            .position(Option::is_some)
+            .unwrap_or(self.list.len())
+            .min(self.shrink_limit());
+        self.list.drain(..leading);
+        self.first_id += leading;
+        if let Some(free_slots) = &mut self.free_slots {
+            let first_id = self.first_id;
+            free_slots.retain(|Reverse(id)| *id >= first_id);
+        }
+        self.list.shrink_to_fit();
+        self.map.shrink_to_fit();
+        self.check_invariants();
+    }
+
+    /// Updates the state that depends on the length of the `Vec` after a shrink.
+    fn finish_shrink(&mut self) {
+        if let Some(free_slots) = &mut self.free_slots {
+            let first_id = self.first_id;
+            free_slots.retain(|Reverse(id)| *id >= first_id);
+        }
+        self.shrink_armed = false;
+        self.last_shrink_len = self.list.len();
+        self.rearm_shrink();
+        self.check_invariants();
+    }
+
+    /// Keeps the IDs in `range` in the `Vec` until `unpin_range` is called with the same range,
+    /// so that lookups of them never hash and their empty slots can still be filled with `set`.
+    /// Shrinks stop short of the lowest pinned ID, which keeps every later slot in the `Vec` as
+    /// well. IDs that are already in the inner `HashMap` stay there. Pins are not serialized.
+    pub fn pin_range(&mut self, range: Range<usize>) {
+        self.pinned.push(range);
+    }
+
+    /// Lifts a pin set with `pin_range` for the same range. Returns whether the range was pinned.
+    /// The `Vec` shrinks past it again on the next removal.
+    pub fn unpin_range(&mut self, range: Range<usize>) -> bool {
+        let Some(idx) = self.pinned.iter().position(|pinned| *pinned == range) else {
+            return false;
+        };
+        self.pinned.swap_remove(idx);
+        return true;
+    }
+
+    /// Returns how many slots at the front of the `Vec` a shrink may drop without dropping a
+    /// pinned ID.
+    fn shrink_limit(&self) -> usize {
+        return self
+            .pinned
+            .iter()
+            .filter(|range| !range.is_empty() && range.end > self.first_id)
+            .map(|range| range.start.saturating_sub(self.first_id))
+            .min()
+            .unwrap_or(self.list.len())
+            .min(self.list.len());
+    }
+
+    /// Returns whether a `Vec` of `len` slots holding `count` items should be shrunk under
+    /// `policy`.
+    fn is_sparse(policy: ShrinkPolicy, count: usize, len: usize) -> bool {
+        if len <= policy.min_len {
+            return false;
+        }
+        return (count as f64) < policy.fill_ratio * len as f64;
+    }
+
+    /// The shrink policy in effect, where the low watermark of the hysteresis takes the place of
+    /// the fill ratio.
+    fn sparse_policy(&self) -> ShrinkPolicy {
+        return match self.hysteresis {
+            Some((low, _)) => ShrinkPolicy {
+                fill_ratio: low,
+                ..self.shrink_policy
+            },
+            None => self.shrink_policy,
+        };
+    }
+
+    /// With hysteresis, a shrink is only allowed once the `Vec` has been filled above the high
+    /// watermark, has been emptied well below the low watermark, or has doubled in length since
+    /// the last shrink.
+    fn shrink_allowed(&self) -> bool {
+        return self.hysteresis.is_none()
+            || self.shrink_armed
+            || self.list.len() >= self.last_shrink_len * 2;
+    }
+
+    /// Allows the next shrink once the `Vec` is filled above the high watermark, or once half of
+    /// the items that the low watermark allowed after the last shrink have been removed, so that
+    /// a list drained by removals alone keeps shrinking.
+    fn rearm_shrink(&mut self) {
+        if let Some((low, high)) = self.hysteresis
+            && !self.shrink_armed
+            && (self.count as f64 > high * self.list.len() as f64
+                || (self.count as f64) < low * self.last_shrink_len as f64 / 2.0)
+        {
+            self.shrink_armed = true;
+        }
+    }
+
+    /// Shrinks the `Vec` in place like the normal shrink, but stops before the inner `HashMap`
+    /// would hold more than `cap` times as many items as the `Vec` has slots. Each slot is only
+    /// checked once it is known it can be dropped, so a shrink that is stopped early costs little.
+    /// Returns whether the cap stopped the shrink.
+    fn shrink_within_map_ratio_cap(&mut self, cap: f64, policy: ShrinkPolicy) -> bool {
+        let original_len = self.list.len();
+        let mut target_count = original_len / 2;
+        let mut shrink_count = 0;
+        let mut migrating = 0;
+        let mut capped = false;
+        let limit = self.shrink_limit();
+        loop {
+            if shrink_count == limit {
+                break;
+            }
+            if shrink_count == target_count {
+                // Keep shrinking while the remaining slots are still sparse
+                let remaining_len = original_len - shrink_count;
+                if !Self::is_sparse(policy, self.count - migrating, remaining_len) {
+                    break;
+                }
+                target_count += remaining_len / 2;
+            }
+            let next_migrating = migrating + usize::from(self.list[shrink_count].is_some());
+            let remaining_len = original_len - shrink_count - 1;
+            if (self.map.len() + next_migrating) as f64 > cap * remaining_len as f64 {
+                capped = true;
+                break;
+            }
+            migrating = next_migrating;
+            shrink_count += 1;
+        }
+
+        self.shrink_visits += shrink_count + usize::from(capped);
+        let first_id = self.first_id;
+        for (idx, value) in self.list.drain(..shrink_count).enumerate() {
+            if let Some(value) = value {
+                Self::migrate(&mut self.map, &mut self.spill_hook, idx + first_id, value);
+            }
+        }
+        self.count -= migrating;
+        self.first_id += shrink_count;
+        return capped;
+    }
+
+    /// Returns whether `compact` would leave the `Vec` dense enough that it does not need to
+    /// shrink again right away. Compacting is pointless otherwise, for example when a single
+    /// old item in the `HashMap` is far below every other ID.
+    fn compacts_densely(&self) -> bool {
+        let Some(lowest_id) = self.map.keys().min() else {
+            return false;
+        };
+        let policy = self.sparse_policy();
+        return !Self::is_sparse(
+            policy,
+            self.count + self.map.len(),
+            self.next_id() - lowest_id,
+        );
+    }
+
+    /// Moves every item in the inner `HashMap` back into the `Vec` so that all lookups avoid
+    /// hashing. The `Vec` is extended at the front down to the lowest ID in the `HashMap`, with
+    /// empty slots for IDs that were already removed. Returns whether any items were moved, and
+    /// returns right away when the `HashMap` is empty.
+    pub fn compact(&mut self) -> bool {
+        let Some(lowest_id) = self.map.keys().min().copied() else {
+            return false;
+        };
+        let offset = self.first_id - lowest_id;
+        self.list.splice(0..0, (0..offset).map(|_| None));
+        for (id, value) in self.map.drain() {
+            self.list[id - lowest_id] = Some(value);
+            self.count += 1;
+        }
+        self.first_id = lowest_id;
+        if let Some(free_slots) = &mut self.free_slots {
+            for idx in 0..offset {
+                if self.list[idx].is_none() {
+                    free_slots.push(Reverse(idx + lowest_id));
+                }
+            }
+        }
+        self.check_invariants();
+        return true;
+    }
+
+    /// Compacts the `ExpirationList` like `compact`, then resizes its allocations so that
+    /// `capacity` returns exactly `target_capacity`. Returns `false` without resizing when the
+    /// compacted `Vec` has more than `target_capacity` slots, counting empty ones.
+    pub fn compact_to(&mut self, target_capacity: usize) -> bool {
+        self.compact();
+        if self.list.len() > target_capacity {
+            return false;
+        }
+        self.map.shrink_to_fit();
+        if self.list.capacity() < target_capacity {
+            self.list.reserve_exact(target_capacity - self.list.len());
+        }
+        self.list.shrink_to(target_capacity);
+        return true;
+    }
+
+    /// Checks the internal consistency of the `ExpirationList`. This can only fail for lists
+    /// built incorrectly with `from_parts` or because of a bug in `ExpirationList` itself.
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        let occupied = self.list.iter().filter(|value| value.is_some()).count();
+        if occupied != self.count {
+            return Err(ValidationError::CountMismatch {
+                count: self.count,
+                occupied,
+            });
+        }
+        if let Some(id) = self.map.keys().find(|id| **id >= self.first_id) {
+            return Err(ValidationError::MapIdInList(*id));
+        }
+        return Ok(());
+    }
+
+    /// Fixes the inconsistencies that `validate` reports and returns how many items in the inner
+    /// `HashMap` had IDs that belong to the `Vec`. Such an item is dropped when the `Vec` has an
+    /// item with the same ID, and moved into the `Vec` otherwise. This salvages lists restored
+    /// from bad data with `from_parts`.
+    pub fn repair(&mut self) -> usize {
+        let first_id = self.first_id;
+        let misplaced: Vec<(usize, T)> = self.map.extract_if(|id, _| *id >= first_id).collect();
+        let repaired = misplaced.len();
+        self.count = self.list.iter().filter(|value| value.is_some()).count();
+        for (id, value) in misplaced {
+            if !self.contains(id) {
+                self.set(id, value);
+            }
+        }
+        return repaired;
+    }
+
+    /// Panics if `validate` fails. This does nothing unless the `debug_invariants` feature is
+    /// enabled.
+    #[inline]
+    fn check_invariants(&self) {
+        #[cfg(feature = "debug_invariants")]
+        if let Err(error) = self.validate() {
+            panic!("ExpirationList invariant violated: {error}");
+        }
+    }
+
+    /// Removes and returns the oldest item if `pred` returns `true` for it. Otherwise the item is
+    /// left in place and `None` is returned.
+    ///
+    /// Finding the oldest item scans the inner `HashMap` and the empty slots at the front of the
+    /// `Vec`, so each call is O(m + h) for m items in the `HashMap` and h leading empty slots.
+    /// Draining many items one call at a time can therefore be quadratic; prefer
+    /// `expire_oldest` or `iter_range` when the number of items to remove is known.
+    pub fn pop_oldest_if(&mut self, pred: impl FnOnce(&T) -> bool) -> Option<(usize, T)> {
+        let id = self.oldest_id()?;
+        if !pred(self.get(id)?) {
+            return None;
+        }
+        return Some((id, self.remove(id)?));
+    }
+
+    /// Removes and returns the oldest item as `Poll::Ready`, or returns `Poll::Pending` when the
+    /// `ExpirationList` is empty. This lets an event loop drain items oldest first without
+    /// blocking. Each call costs the same as `pop_oldest_if`.
+    pub fn poll_oldest(&mut self) -> Poll<(usize, T)> {
+        return match self.pop_oldest_if(|_| true) {
+            Some(item) => Poll::Ready(item),
+            None => Poll::Pending,
+        };
+    }
+
+    /// Returns the lowest ID in the `Vec` whose item was removed, which is the slot the free list
+    /// would reuse first, or `None` if every slot in the `Vec` holds an item.
+    pub fn first_free_id(&self) -> Option<usize> {
+        return self
+            .list
+            .iter()
+            .position(Option::is_none)
+            .map(|idx| idx + self.first_id);
+    }
+
+    /// Returns the lowest ID of any item. This is O(m + h), see `pop_oldest_if`.
+    fn oldest_id(&self) -> Option<usize> {
+        if let Some(id) = self.map.keys().min() {
+            return Some(*id);
+        }
+        return self
+            .list
+            .iter()
+            .position(Option::is_some)
+            .map(|idx| idx + self.first_id);
+    }
+
+    /// Removes every item if `pred` returns `true` for the `ExpirationList` and returns whether
+    /// it did. IDs handed out before the clear are not reused.
+    pub fn clear_if(&mut self, pred: impl FnOnce(&Self) -> bool) -> bool {
+        if !pred(self) {
+            return false;
+        }
+        self.clear();
+        return true;
+    }
+
+    /// Removes and returns every item in ascending ID order, then starts handing out IDs from 0
+    /// again. Unlike a clear, IDs are reused after a reset, so this should only be used when no
+    /// IDs from before the reset are still held anywhere. Pins and the count of slow lookups for
+    /// `with_auto_compact` are dropped as well, since they refer to the old IDs.
+    pub fn reset(&mut self) -> Vec<(usize, T)> {
+        let mut items: Vec<(usize, T)> = self.map.drain().collect();
+        items.sort_unstable_by_key(|(id, _)| *id);
+        let first_id = self.first_id;
+        items.extend(
+            self.list
+                .drain(..)
+                .enumerate()
+                .filter_map(|(idx, value)| Some((idx + first_id, value?))),
+        );
+        self.first_id = 0;
+        self.base_id = 0;
+        self.count = 0;
+        if let Some(free_slots) = &mut self.free_slots {
+            free_slots.clear();
+        }
+        self.shrink_armed = true;
+        self.last_shrink_len = 0;
+        self.pinned.clear();
+        *self.slow_hits.get_mut() = 0;
+        return items;
+    }
+
+    /// Removes every item while keeping the allocations. The next ID continues from where it was,
+    /// so IDs handed out before the clear are never reused. Use `reset` to start from 0 again.
+    pub fn clear(&mut self) {
+        self.first_id = self.next_id();
+        self.count = 0;
+        self.list.clear();
+        self.map.clear();
+        if let Some(free_slots) = &mut self.free_slots {
+            free_slots.clear();
+        }
+        self.shrink_armed = true;
+        self.last_shrink_len = 0;
+    }
+
+    /// Removes every item like `clear` and returns an iterator over the removed items, in the
+    /// same order as the iterator. The `ExpirationList` is empty as soon as this is called, and
+    /// it keeps its allocations for reuse.
+    pub fn drain(&mut self) -> ExpirationListDrain<'_, T> {
+        let list_id = self.first_id;
+        self.first_id = self.next_id();
+        self.count = 0;
+        if let Some(free_slots) = &mut self.free_slots {
+            free_slots.clear();
+        }
+        self.shrink_armed = true;
+        self.last_shrink_len = 0;
+        return ExpirationListDrain {
+            map_iter: self.map.drain(),
+            list_iter: self.list.drain(..),
+            list_id,
+        };
+    }
+
+    /// Returns the highest ID of any item.
+    fn newest_id(&self) -> Option<usize> {
+        if let Some(idx) = self.list.iter().rposition(Option::is_some) {
+            return Some(idx + self.first_id);
+        }
+        return self.map.keys().max().copied();
+    }
+
+    /// Removes an item by ID like `remove`, but reports why nothing was removed so that stale IDs
+    /// can be told apart from IDs that were never handed out. IDs below the base given to
+    /// `with_capacity_and_first_id` are never issued. After a `reset`, IDs are judged against the
+    /// new ID sequence only, and lists built with `from_parts` treat every ID below `first_id` as
+    /// issued.
+    pub fn try_remove(&mut self, id: usize) -> Result<T, RemoveError> {
+        return self.remove(id).ok_or_else(|| self.missing_reason(id));
+    }
+
+    /// Explains why there is no item with the given ID.
+    fn missing_reason(&self, id: usize) -> RemoveError {
+        if id < self.base_id || id >= self.next_id() {
+            return RemoveError::NeverIssued;
+        }
+        return RemoveError::AlreadyRemoved;
+    }
+
+    /// Returns the ID that the next item added will get, unless the free list reuses an ID. IDs
+    /// only ever increase, so `usize::MAX - next_id()` is the number of IDs left.
+    pub fn next_id(&self) -> usize {
+        self.first_id + self.list.len()
+    }
+
+    /// Returns the lowest free slot in the `Vec` when the free list is enabled.
+    fn pop_free_slot(&mut self) -> Option<usize> {
+        let free_slots = self.free_slots.as_mut()?;
+        while let Some(Reverse(id)) = free_slots.pop() {
+            if id >= self.first_id && matches!(self.list.get(id - self.first_id), Some(None)) {
+                return Some(id);
+            }
+        }
+        return None;
+    }
+
+    /// Removes up to `n` of the oldest items and returns them with their IDs in ascending ID order.
+    pub fn expire_oldest(&mut self, n: usize) -> Vec<(usize, T)> {
+        let mut ids: Vec<usize> = self.map.keys().copied().collect();
+        ids.sort_unstable();
+        ids.truncate(n);
+
+        let first_id = self.first_id;
+        let remaining = n - ids.len();
+        ids.extend(
+            self.list
+                .iter()
+                .enumerate()
+                .filter(|(_, value)| value.is_some())
+                .map(|(idx, _)| idx + first_id)
+                .take(remaining),
+        );
+
+        return ids
+            .into_iter()
+            .filter_map(|id| Some((id, self.remove(id)?)))
+            .collect();
+    }
+
+    /// Takes an item ID and returns `Some(item: &T)` when the item is found and `None` otherwise.
+    pub fn get(&self, id: usize) -> Option<&T> {
+        if id < self.first_id {
+            let value = self.map.get(&id);
+            Self::count_lookup(self.auto_compact, &self.slow_hits, value.is_some());
+            return value;
+        }
+        Self::count_lookup(self.auto_compact, &self.slow_hits, false);
+        return self.list.get(id - self.first_id)?.as_ref();
+    }
+
+    /// Takes an item ID and returns `Some(item: &mut T)` when the item is found and `None`
+    /// otherwise.
+    pub fn get_mut(&mut self, id: usize) -> Option<&mut T> {
+        self.auto_compact_now();
+        if id < self.first_id {
+            let value = self.map.get_mut(&id);
+            Self::count_lookup(self.auto_compact, &self.slow_hits, value.is_some());
+            return value;
+        }
+        Self::count_lookup(self.auto_compact, &self.slow_hits, false);
+        return self.list.get_mut(id - self.first_id)?.as_mut();
+    }
+
+    /// Counts a lookup towards `with_auto_compact`. Lookups that did not find their item in the
+    /// inner `HashMap` restart the count.
+    #[inline]
+    fn count_lookup(auto_compact: Option<usize>, slow_hits: &AtomicUsize, slow_hit: bool) {
+        if auto_compact.is_none() {
+            return;
+        }
+        if slow_hit {
+            slow_hits.fetch_add(1, AtomicOrdering::Relaxed);
+        } else {
+            slow_hits.store(0, AtomicOrdering::Relaxed);
+        }
+    }
+
+    /// Compacts the `ExpirationList` if `with_auto_compact` has seen enough lookups in the inner
+    /// `HashMap` in a row.
+    fn auto_compact_now(&mut self) {
+        let Some(threshold) = self.auto_compact else {
+            return;
+        };
+        if *self.slow_hits.get_mut() < threshold {
+            return;
+        }
+        *self.slow_hits.get_mut() = 0;
+        if self.compacts_densely() {
+            self.compact();
+        }
+    }
+
+    /// Takes an item ID and returns `&mut T`, panicking with `msg` and the state of the
+    /// `ExpirationList` when the item is not found.
+    pub fn get_mut_expect(&mut self, id: usize, msg: &str) -> &mut T {
+        if self.get(id).is_none() {
+            panic!(
+                "{msg}: no item with id {id} ({}, first_id {}, next_id {})",
+                self.missing_reason(id),
+                self.first_id,
+                self.next_id()
+            );
+        }
+        return self.get_mut(id).expect("the item was just found");
+    }
+
+    /// Returns mutable references to the items with each of `ids` at once, for example to swap
+    /// fields between two items. Returns `None` if any of the IDs has no item or if any ID is
+    /// given more than once.
+    pub fn get_disjoint_mut<const N: usize>(&mut self, ids: [usize; N]) -> Option<[&mut T; N]> {
+        for (idx, id) in ids.iter().enumerate() {
+            if ids[..idx].contains(id) {
+                return None;
+            }
+        }
+        self.auto_compact_now();
+        if !ids.iter().all(|id| self.contains(*id)) {
+            return None;
+        }
+        let first_id = self.first_id;
+        // Every pointer into the `Vec` is derived from this one pointer, because each call to
+        // `get_mut` on the `Vec` would reborrow all of it and invalidate the earlier pointers.
+        let list_ptr = self.list.as_mut_ptr();
+        let mut pointers = [core::ptr::null_mut::<T>(); N];
+        for (pointer, id) in pointers.iter_mut().zip(ids) {
+            let value = if id < first_id {
+                self.map.get_mut(&id)
+            } else {
+                // SAFETY: `contains` checked that the slot is in bounds and holds an item.
+                unsafe { (*list_ptr.add(id - first_id)).as_mut() }
+            };
+            *pointer = value?;
+        }
+        // SAFETY: The IDs are distinct, so every pointer is to a different item in either the
+        // `Vec` or the `HashMap`. Nothing was added, removed or moved while the pointers were
+        // taken, so they all stay valid for as long as `self` is mutably borrowed.
+        return Some(pointers.map(|pointer| unsafe { &mut *pointer }));
+    }
+
+    /// Looks up each of `ids` and returns the items that were found keyed by their ID.
+    pub fn get_subset<'a>(&'a self, ids: &[usize]) -> FnvHashMap<usize, &'a T> {
+        return ids
+            .iter()
+            .filter_map(|id| Some((*id, self.get(*id)?)))
+            .collect();
+    }
+
+    /// Returns `true` if an item with the given ID exists. Returns `false` otherwise.
+    pub fn contains(&self, id: usize) -> bool {
+        if id < self.first_id {
+            return self.map.contains_key(&id);
+        } else if let Some(Some(_)) = self.list.get(id - self.first_id) {
+            return true;
+        }
+        return false;
+    }
+
+    /// Returns the IDs of all items as ranges in ascending order. Items in the `Vec` are grouped
+    /// into maximal runs of consecutive IDs while items in the `HashMap` are each returned as a
+    /// single ID range.
+    pub fn live_ranges(&self) -> impl Iterator<Item = Range<usize>> + '_ {
+        let mut map_ids: Vec<usize> = self.map.keys().copied().collect();
+        map_ids.sort_unstable();
+
+        let mut idx = 0;
+        let list_ranges = core::iter::from_fn(move || {
+            while idx < self.list.len() && self.list[idx].is_none() {
+                idx += 1;
+            }
+            let start = idx;
+            while idx < self.list.len() && self.list[idx].is_some() {
+                idx += 1;
+            }
+            return (start < idx).then(|| (self.first_id + start)..(self.first_id + idx));
+        });
+
+        return map_ids.into_iter().map(|id| id..id + 1).chain(list_ranges);
+    }
+
+    /// Consumes the `ExpirationList` and returns its items with their IDs in ascending ID order.
+    pub fn into_iter_sorted(self) -> impl Iterator<Item = (usize, T)> {
+        let mut map_items: Vec<(usize, T)> = self.map.into_iter().collect();
+        map_items.sort_unstable_by_key(|(id, _)| *id);
+        let first_id = self.first_id;
+        let list_items = self
+            .list
+            .into_iter()
+            .enumerate()
+            .filter_map(move |(idx, value)| Some((idx + first_id, value?)));
+        return map_items.into_iter().chain(list_items);
+    }
+
+    /// Consumes the `ExpirationList` and returns its items in a `HashMap` keyed by ID, for code
+    /// that does not know about `ExpirationList`. The inner `HashMap` is reused, with room made
+    /// for the items of the `Vec`.
+    pub fn into_hashmap(mut self) -> HashMap<usize, T, S> {
+        self.map.reserve(self.count);
+        let first_id = self.first_id;
+        self.map.extend(
+            self.list
+                .into_iter()
+                .enumerate()
+                .filter_map(|(idx, value)| Some((idx + first_id, value?))),
+        );
+        return self.map;
+    }
+
+    /// Returns an iterator over clones of every item in ascending ID order that owns `list`, so a
+    /// published snapshot can be iterated without borrowing from a local binding. Wrap `T` in an
+    /// `Arc` to make the clones cheap.
+    pub fn snapshot_iter(list: Arc<Self>) -> ExpirationListSnapshotIter<T, S> {
+        let mut map_ids: Vec<usize> = list.map.keys().copied().collect();
+        map_ids.sort_unstable();
+        return ExpirationListSnapshotIter {
+            list,
+            map_ids,
+            map_pos: 0,
+            list_idx: 0,
+        };
+    }
+
+    /// Returns an iterator over the IDs of every item, in the same order as the iterator.
+    pub fn keys(&self) -> impl Iterator<Item = usize> + '_ {
+        return self.into_iter().map(|(id, _)| id);
+    }
+
+    /// Returns an iterator over every item without its ID, in the same order as the iterator.
+    pub fn values(&self) -> impl Iterator<Item = &T> + '_ {
+        return self.into_iter().map(|(_, value)| value);
+    }
+
+    /// Returns an iterator over mutable references to every item, in the same order as the
+    /// shared iterator.
+    pub fn iter_mut(&mut self) -> ExpirationListIterMut<'_, T> {
+        return self.into_iter();
+    }
+
+    /// Returns a `LendingIterator` over mutable references to every item in ascending ID order.
+    pub fn lending_iter_mut(&mut self) -> ExpirationListLendingIter<'_, T, S> {
+        let mut map_ids: Vec<usize> = self.map.keys().copied().collect();
+        map_ids.sort_unstable();
+        let list_id = self.first_id;
+        return ExpirationListLendingIter {
+            list: self,
+            map_ids,
+            map_pos: 0,
+            list_id,
+        };
+    }
+
+    /// Returns an iterator that alternates between items in the `Vec` and items in the `HashMap`
+    /// until one of them runs out, so that long processing loops do not starve the older items.
+    pub fn iter_fair(&self) -> impl Iterator<Item = (usize, &T)> {
+        let first_id = self.first_id;
+        let mut list_iter = self
+            .list
+            .iter()
+            .enumerate()
+            .filter_map(move |(idx, value)| Some((idx + first_id, value.as_ref()?)));
+        let mut map_iter = self.map.iter().map(|(id, value)| (*id, value));
+        let mut from_list = true;
+        return core::iter::from_fn(move || {
+            let next = if from_list {
+                list_iter.next().or_else(|| map_iter.next())
+            } else {
+                map_iter.next().or_else(|| list_iter.next())
+            };
+            from_list = !from_list;
+            return next;
+        });
+    }
+
+    /// Returns an iterator over mutable references to every item in descending ID order, so the
+    /// newest item comes first. The `HashMap` keys are sorted, which takes O(m log m) time and
+    /// O(m) extra memory for m items in the `HashMap`.
+    pub fn iter_mut_rev(&mut self) -> impl Iterator<Item = (usize, &mut T)> {
+        let mut map_items: Vec<(usize, &mut T)> = self
+            .map
+            .iter_mut()
+            .map(|(id, value)| (*id, value))
+            .collect();
+        map_items.sort_unstable_by_key(|(id, _)| Reverse(*id));
+        let first_id = self.first_id;
+        let list_items = self
+            .list
+            .iter_mut()
+            .enumerate()
+            .rev()
+            .filter_map(move |(idx, value)| Some((idx + first_id, value.as_mut()?)));
+        return list_items.chain(map_items);
+    }
+
+    /// Returns an iterator over the items together with their age, which is how many IDs older
+    /// they are than the newest item. The newest item has an age of 0.
+    pub fn iter_with_age(&self) -> impl Iterator<Item = (usize, &T, usize)> {
+        let newest_id = self.newest_id().unwrap_or(0);
+        return self
+            .into_iter()
+            .map(move |(id, value)| (id, value, newest_id - id));
+    }
+
+    /// Returns an iterator over the items whose IDs satisfy `id % modulus == residue`, so that
+    /// `modulus` workers can each take one residue and split the items without coordinating. The
+    /// `Vec` is stepped through directly rather than filtered.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `modulus` is 0 or `residue` is not below `modulus`, since no ID would match.
+    pub fn iter_stride(&self, modulus: usize, residue: usize) -> impl Iterator<Item = (usize, &T)> {
+        assert!(modulus > 0, "iter_stride needs a modulus above 0");
+        assert!(
+            residue < modulus,
+            "iter_stride needs a residue below the modulus"
+        );
+        let first_id = self.first_id;
+        let start_idx = (residue + modulus - first_id % modulus) % modulus;
+        let map_items = self
+            .map
+            .iter()
+            .filter(move |(id, _)| **id % modulus == residue)
+            .map(|(id, value)| (*id, value));
+        let list_items = self
+            .list
+            .iter()
+            .enumerate()
+            .skip(start_idx)
+            .step_by(modulus)
+            .filter_map(move |(idx, value)| Some((idx + first_id, value.as_ref()?)));
+        return map_items.chain(list_items);
+    }
+
+    /// Returns an iterator over the items as `(reported_id, recomputed_id, &T)`. The reported ID
+    /// is the one the normal iterator gives, while the recomputed ID comes from the `HashMap` key
+    /// or the position in the `Vec`. The two always match unless the ID arithmetic has a bug, so
+    /// this is meant for tests of the shrink logic.
+    pub fn debug_iter(&self) -> impl Iterator<Item = (usize, usize, &T)> {
+        let first_id = self.first_id;
+        let list_ids = self
+            .list
+            .iter()
+            .enumerate()
+            .filter(|(_, value)| value.is_some())
+            .map(move |(idx, _)| idx + first_id);
+        let recomputed_ids = self.map.keys().copied().chain(list_ids);
+        return self
+            .into_iter()
+            .zip(recomputed_ids)
+            .map(|((id, value), recomputed_id)| (id, recomputed_id, value));
+    }
+
+    /// Calls `f` on every item in the same order as the iterator and stops at the first error,
+    /// returning it together with the ID of the item that caused it.
+    pub fn try_for_each<E>(
+        &self,
+        mut f: impl FnMut(usize, &T) -> Result<(), E>,
+    ) -> Result<(), (usize, E)> {
+        for (id, value) in &self.map {
+            f(*id, value).map_err(|error| (*id, error))?;
+        }
+        for (idx, value) in self.list.iter().enumerate() {
+            if let Some(value) = value {
+                let id = idx + self.first_id;
+                f(id, value).map_err(|error| (id, error))?;
+            }
+        }
+        return Ok(());
+    }
+
+    /// Splits the items into those for which `pred` returns `true` and those for which it returns
+    /// `false` in a single pass, both in the same order as the iterator.
+    #[allow(clippy::type_complexity)]
+    pub fn partition_collect(
+        &self,
+        pred: impl Fn(&T) -> bool,
+    ) -> (Vec<(usize, &T)>, Vec<(usize, &T)>) {
+        return self.into_iter().partition(|(_, value)| pred(value));
+    }
+
+    /// Counts the items for each key returned by `key` in a single pass, for example to count
+    /// items by state.
+    pub fn histogram_by<K: Eq + Hash>(&self, key: impl Fn(&T) -> K) -> HashMap<K, usize> {
+        let mut counts = HashMap::new();
+        for (_, value) in self {
+            *counts.entry(key(value)).or_insert(0) += 1;
+        }
+        return counts;
+    }
+
+    /// Returns clones of every item in ascending ID order, without their IDs.
+    pub fn to_vec(&self) -> Vec<T>
+    where
+        T: Clone,
+    {
+        let mut values = Vec::with_capacity(self.len());
+        values.extend(self.iter_range(..).map(|(_, value)| value.clone()));
+        return values;
+    }
+
+    /// Returns clones of the items for which `pred` returns `true` in ascending ID order, for
+    /// example to send them to another thread.
+    pub fn snapshot_filtered(&self, pred: impl Fn(usize, &T) -> bool) -> Vec<(usize, T)>
+    where
+        T: Clone,
+    {
+        return self
+            .iter_range(..)
+            .filter(|(id, value)| pred(*id, value))
+            .map(|(id, value)| (id, value.clone()))
+            .collect();
+    }
+
+    /// Groups the items into batches in ascending ID order so that the total `weight` of each
+    /// batch is at most `max_weight`. An item that weighs more than `max_weight` on its own gets a
+    /// batch to itself.
+    pub fn weighted_batches<'a>(
+        &'a self,
+        weight: impl Fn(&T) -> usize + 'a,
+        max_weight: usize,
+    ) -> impl Iterator<Item = Vec<(usize, &'a T)>> {
+        // Weigh each item once as it is peeked, rather than again when it starts the next batch
+        let mut items = self
+            .iter_range(..)
+            .map(move |item| (weight(item.1), item))
+            .peekable();
+        return core::iter::from_fn(move || {
+            let (mut total, first) = items.next()?;
+            let mut batch = vec![first];
+            while let Some(&(item_weight, _)) = items.peek() {
+                // A total that overflows is over any `max_weight`
+                match total.checked_add(item_weight) {
+                    Some(next_total) if next_total <= max_weight => total = next_total,
+                    _ => break,
+                }
+                batch.extend(items.next().map(|(_, item)| item));
+            }
+            return Some(batch);
+        });
+    }
+
+    /// Returns an iterator over the items whose IDs are not in `exclude`.
+    pub fn iter_excluding<'a, H: BuildHasher>(
+        &'a self,
+        exclude: &'a HashSet<usize, H>,
+    ) -> impl Iterator<Item = (usize, &'a T)> {
+        return self.into_iter().filter(|(id, _)| !exclude.contains(id));
+    }
+
+    /// Returns an iterator over the items with the given IDs in ascending ID order. The IDs are
+    /// copied and sorted, then each one is looked up directly, so this is O(k log k) time and
+    /// O(k) extra memory for k IDs rather than a scan of every item.
+    pub fn iter_matching<'a>(&'a self, ids: &'a [usize]) -> impl Iterator<Item = (usize, &'a T)> {
+        let mut ids = ids.to_vec();
+        ids.sort_unstable();
+        ids.dedup();
+        return ids.into_iter().filter_map(|id| Some((id, self.get(id)?)));
+    }
+
+    /// Returns an iterator that skips any ID stored in both the `Vec` and the `HashMap`, preferring
+    /// the item in the `Vec`. This can only happen when a list was built incorrectly with
+    /// `from_parts`, and is meant as a safety net for restoring corrupted data.
+    pub fn iter_strict(&self) -> ExpirationListStrictIter<'_, T, S> {
+        ExpirationListStrictIter {
+            iter: self.into_iter(),
+            list: self,
+            anomalies: 0,
+        }
+    }
+
+    /// Returns an iterator over the items with IDs in `range` in ascending ID order.
+    pub fn iter_range(&self, range: impl RangeBounds<usize>) -> ExpirationListRangeIter<'_, T, S> {
+        let (start_id, end_id) = self.id_bounds(range);
+        let mut map_ids: Vec<usize> = self
+            .map
+            .keys()
+            .copied()
+            .filter(|id| (start_id..end_id).contains(id))
+            .collect();
+        map_ids.sort_unstable();
+        ExpirationListRangeIter {
+            list: self,
+            map_ids,
+            map_pos: 0,
+            list_id: start_id.max(self.first_id),
+            end_id,
+        }
+    }
+
+    /// Returns an iterator over mutable references to the items with IDs in `range` in ascending
+    /// ID order. Only the part of the `Vec` within `range` is visited, and the `HashMap` is
+    /// skipped when `range` starts in the `Vec`.
+    pub fn range_mut(
+        &mut self,
+        range: impl RangeBounds<usize>,
+    ) -> impl Iterator<Item = (usize, &mut T)> {
+        let (start_id, end_id) = self.id_bounds(range);
+        let first_id = self.first_id;
+        let mut map_items: Vec<(usize, &mut T)> = Vec::new();
+        if start_id < first_id {
+            map_items.extend(
+                self.map
+                    .iter_mut()
+                    .filter(|(id, _)| (start_id..end_id).contains(*id))
+                    .map(|(id, value)| (*id, value)),
+            );
+            map_items.sort_unstable_by_key(|(id, _)| *id);
+        }
+        let start_idx = start_id.max(first_id) - first_id;
+        let end_idx = end_id.max(first_id) - first_id;
+        let list_items = self.list[start_idx..end_idx]
+            .iter_mut()
+            .enumerate()
+            .filter_map(move |(idx, value)| Some((idx + start_idx + first_id, value.as_mut()?)));
+        return map_items.into_iter().chain(list_items);
+    }
+
+    /// Iterates over the items of this list and `other` together in ascending ID order, which is
+    /// useful when both lists share one ID space. Each ID is yielded once and tagged with the
+    /// list or lists it was found in.
+    pub fn merge_iter<'a>(
+        &'a self,
+        other: &'a ExpirationList<T, S>,
+    ) -> impl Iterator<Item = (usize, MergeSide<&'a T>)> {
+        let mut left = self.iter_range(..).peekable();
+        let mut right = other.iter_range(..).peekable();
+        return core::iter::from_fn(move || {
+            let side = match (left.peek(), right.peek()) {
+                (Some((left_id, _)), Some((right_id, _))) => left_id.cmp(right_id),
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (None, None) => return None,
+            };
+            return match side {
+                Ordering::Less => left.next().map(|(id, value)| (id, MergeSide::Left(value))),
+                Ordering::Greater => right
+                    .next()
+                    .map(|(id, value)| (id, MergeSide::Right(value))),
+                Ordering::Equal => {
+                    let (id, left_value) = left.next()?;
+                    let (_, right_value) = right.next()?;
+                    Some((id, MergeSide::Both(left_value, right_value)))
+                }
+            };
+        });
+    }
+
+    /// Yields the IDs that have an item in both this list and `other`, paired with both items, in
+    /// ascending ID order. This is an inner join of two lists that share one ID space, such as
+    /// component storages.
+    pub fn zip_ids<'a, U>(
+        &'a self,
+        other: &'a ExpirationList<U, S>,
+    ) -> impl Iterator<Item = (usize, &'a T, &'a U)> {
+        return self
+            .iter_range(..)
+            .filter_map(|(id, value)| Some((id, value, other.get(id)?)));
+    }
+
+    /// Compares this list against a `previous` version of it and yields the changes in ascending
+    /// ID order. Applying the changes to `previous` reproduces this list.
+    pub fn diff<'a>(
+        &'a self,
+        previous: &'a ExpirationList<T, S>,
+    ) -> impl Iterator<Item = Change<'a, T>>
+    where
+        T: PartialEq,
+    {
+        return self
+            .merge_iter(previous)
+            .filter_map(|(id, side)| match side {
+                MergeSide::Left(value) => Some(Change::Added(id, value)),
+                MergeSide::Right(_) => Some(Change::Removed(id)),
+                MergeSide::Both(value, old_value) if value != old_value => {
+                    Some(Change::Modified(id, value))
+                }
+                MergeSide::Both(_, _) => None,
+            });
+    }
+
+    /// Applies changes from `diff` so that this list matches the list they were taken from.
+    /// Added and modified items are cloned in with `set` and removed items are removed.
+    pub fn apply_diff<'a>(&mut self, changes: impl IntoIterator<Item = Change<'a, T>>)
+    where
+        T: Clone + 'a,
+    {
+        for change in changes {
+            match change {
+                Change::Added(id, value) | Change::Modified(id, value) => {
+                    self.set(id, value.clone());
+                }
+                Change::Removed(id) => {
+                    self.remove(id);
+                }
+            }
+        }
+    }
+
+    /// Returns the runs of equal items with consecutive IDs as `(start_id, value, run_length)` in
+    /// ascending ID order. A removed ID ends a run.
+    pub fn runs(&self) -> impl Iterator<Item = (usize, &T, usize)>
+    where
+        T: PartialEq,
+    {
+        let mut items = self.iter_range(..).peekable();
+        return core::iter::from_fn(move || {
+            let (start_id, value) = items.next()?;
+            let mut run_length = 1;
+            while items
+                .next_if(|(id, next)| *id == start_id + run_length && *next == value)
+                .is_some()
+            {
+                run_length += 1;
+            }
+            return Some((start_id, value, run_length));
+        });
+    }
+
+    /// Returns a cursor over the items in ascending ID order, starting before the first item.
+    pub fn cursor(&self) -> Cursor<'_, T, S> {
+        let mut map_ids: Vec<usize> = self.map.keys().copied().collect();
+        map_ids.sort_unstable();
+        Cursor {
+            list: self,
+            map_ids,
+            current_id: None,
+        }
+    }
+
+    /// Converts a range of IDs into an inclusive start and exclusive end, where the end is at most
+    /// `next_id` and the start is at most the end, so empty and inverted ranges can be sliced.
+    fn id_bounds(&self, range: impl RangeBounds<usize>) -> (usize, usize) {
+        let start_id = match range.start_bound() {
+            Bound::Included(id) => *id,
+            Bound::Excluded(id) => id.saturating_add(1),
+            Bound::Unbounded => 0,
+        };
+        let end_id = match range.end_bound() {
+            Bound::Included(id) => id.saturating_add(1),
+            Bound::Excluded(id) => *id,
+            Bound::Unbounded => usize::MAX,
+        };
+        let end_id = end_id.min(self.next_id());
+        return (start_id.min(end_id), end_id);
+    }
+
+    /// Returns which inner structure holds the item with the given ID, or `None` if there is no
+    /// such item. This is useful for profiling how often lookups hit the `HashMap`.
+    pub fn residence(&self, id: usize) -> Option<Region> {
+        if id < self.first_id {
+            return self.map.contains_key(&id).then_some(Region::Map);
+        }
+        return self
+            .list
+            .get(id - self.first_id)?
+            .as_ref()
+            .map(|_| Region::List);
+    }
+
+    /// Returns the number of items in both the `Vec` and the `HashMap`. This is O(1).
+    pub fn len(&self) -> usize {
+        self.count + self.map.len()
+    }
+
+    /// Returns `true` if the `ExpirationList` holds no items.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns where the items are stored, for example to see whether a workload mostly hits the
+    /// `Vec` or has drifted into the `HashMap`. This is O(n) for the slots of the `Vec` and the
+    /// items in the `HashMap`.
+    pub fn stats(&self) -> Stats {
+        let duplicate_ids = self
+            .map
+            .keys()
+            .filter(|id| {
+                **id >= self.first_id && matches!(self.list.get(*id - self.first_id), Some(Some(_)))
+            })
+            .count();
+        return Stats {
+            list_len: self.list.len(),
+            list_capacity: self.list.capacity(),
+            map_len: self.map.len(),
+            live_count: self.len(),
+            dead_slots: self.list.iter().filter(|value| value.is_none()).count(),
+            first_id: self.first_id,
+            duplicate_ids,
+            shrink_visits: self.shrink_visits,
+            removals: self.removals,
+        };
+    }
+
+    /// Returns the combined capacity of the `Vec` and the `HashMap`.
+    pub fn capacity(&self) -> usize {
+        self.list.capacity() + self.map.capacity()
+    }
+
+    /// Returns the raw slots of the inner `Vec` in chunks of `chunk` slots, each paired with the ID
+    /// of its first slot. Removed items are left in place as `None` so the chunks can be processed
+    /// with a mask. Items that have been moved to the inner `HashMap` are not included.
+    ///
+    /// Panics if `chunk` is 0.
+    pub fn list_chunks(&self, chunk: usize) -> impl Iterator<Item = (usize, &[Option<T>])> {
+        let first_id = self.first_id;
+        return self
+            .list
+            .chunks(chunk)
+            .enumerate()
+            .map(move |(idx, slots)| (first_id + idx * chunk, slots));
+    }
+
+    /// Returns a pointer to the slots of the inner `Vec` together with the number of slots and
+    /// the ID of the first slot, so the slots can be read from outside of Rust. The slot at index
+    /// `i` holds the item with ID `first_id + i`, or `None` if it was removed. Items that have
+    /// been moved to the inner `HashMap` are not included.
+    ///
+    /// # Pointer validity
+    ///
+    /// The pointer is only valid until the `ExpirationList` is dropped or any method taking
+    /// `&mut self` is called, even one that does not change the items, since it may move or free
+    /// the `Vec`. It must only be used to read. The layout of `Option<T>` is only defined for some `T`, such as
+    /// `Option<&U>`, `Option<Box<U>>` and `Option<NonZeroU32>`, which are stored like a nullable
+    /// pointer or integer. For other types, foreign code needs a `#[repr(C)]` mirror of the layout
+    /// rustc chose, and should prefer copying the items into an FFI-safe buffer.
+    pub fn list_as_ptr(&self) -> (*const Option<T>, usize, usize) {
+        return (self.list.as_ptr(), self.list.len(), self.first_id);
+    }
+
+    /// Reserves capacity for at least `additional` more items in the inner `HashMap`. Use this
+    /// ahead of a large batch of removals that is known to migrate items out of the `Vec` so the
+    /// shrink does not have to grow the `HashMap` part way through.
+    pub fn reserve_map(&mut self, additional: usize) {
+        self.map.reserve(additional);
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+
+    thread_local! {
+        // Allocations on this thread larger than this many bytes fail.
+        static ALLOC_LIMIT: Cell<usize> = const { Cell::new(usize::MAX) };
+        // The largest allocation made on this thread since it was last reset.
+        static LARGEST_ALLOC: Cell<usize> = const { Cell::new(0) };
+    }
+
+    /// Fails allocations over the current thread's `ALLOC_LIMIT` so tests can hit reserve errors,
+    /// and records the largest allocation in `LARGEST_ALLOC`.
+    struct LimitedAlloc;
+
+    fn within_alloc_limit(size: usize) -> bool {
+        let _ = LARGEST_ALLOC.try_with(|largest| largest.set(largest.get().max(size)));
+        return ALLOC_LIMIT
+            .try_with(|limit| size <= limit.get())
+            .unwrap_or(true);
+    }
+
+    unsafe impl GlobalAlloc for LimitedAlloc {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            if !within_alloc_limit(layout.size()) {
+                return std::ptr::null_mut();
+            }
+            return unsafe { System.alloc(layout) };
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            unsafe { System.dealloc(ptr, layout) };
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            if !within_alloc_limit(new_size) {
+                return std::ptr::null_mut();
+            }
+            return unsafe { System.realloc(ptr, layout, new_size) };
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: LimitedAlloc = LimitedAlloc;
+
+    /// Returns a list of IDs 0 to 63 holding their own ID, where only every third item is left.
+    /// Removing the others shrinks the `Vec`, so the items are split between the `HashMap` and
+    /// the `Vec`.
+    fn sparse_list() -> ExpirationList<usize> {
+        let mut list = ExpirationList::new();
+        for idx in 0..64 {
+            list.add(idx);
+        }
+        for idx in (0..64).filter(|idx| idx % 3 != 0) {
+            list.remove(idx);
+        }
+        assert!(!list.map.is_empty() && list.count > 0);
+        return list;
+    }
+
+    #[test]
+    fn it_counts_elements() {
+        let mut list = ExpirationList::new();
+        for idx in 0..1024 {
+            list.add(idx);
+        }
+        assert_eq!(list.count, 1024);
+
+        for idx in 0..513 {
+            list.remove(idx);
+        }
+        assert_eq!(list.count, 1024 - 513);
+
+        for idx in 0..260 {
+            list.remove(idx + 600); // 513 - 599 should be not removed
+        }
+        assert_eq!(list.count, 1024 - 513 - 260 - (600 - 513));
+        assert_eq!(list.map.len(), 600 - 513);
+
+        // Check that the list has been reduced to 256 items
+        assert_eq!(list.first_id, 512 + 256);
+        assert_eq!(list.list.len(), 256);
+
+        let stats = list.stats();
+        assert_eq!(stats.list_len, 256);
+        assert!(stats.list_capacity >= 256);
+        assert_eq!(stats.map_len, 600 - 513);
+        assert_eq!(stats.live_count, 1024 - 513 - 260);
+        assert_eq!(stats.dead_slots, 256 - list.count);
+        assert_eq!(stats.first_id, 512 + 256);
+        assert_eq!(stats.duplicate_ids, 0);
+        assert_eq!(stats.removals, 513 + 260);
+        assert_eq!(
+            stats.live_count,
+            stats.map_len + stats.list_len - stats.dead_slots
+        );
+    }
+
+    #[test]
+    fn it_adds_and_gets() {
+        let mut list = ExpirationList::new();
+        for idx in 0..1024 {
+            list.add(idx);
+        }
+        for idx in 0..1024 {
+            assert_eq!(list.get(idx), Some(&idx));
+        }
+    }
+
+    #[test]
+    fn it_gets_after_remove() {
+        let mut list = ExpirationList::new();
+        for idx in 0..1024 {
+            list.add(idx);
+        }
+        for idx in 0..513 {
+            assert_eq!(list.remove(idx), Some(idx as i32));
+        }
+        assert_eq!(list.get(100), None);
+        assert_eq!(list.remove(100), None);
+        assert_eq!(list.get(600), Some(&600));
+        assert_eq!(list.remove(600), Some(600));
+        assert_eq!(list.get(600), None);
+
+        list.into_iter();
+    }
+
+    #[test]
+    fn it_is_iterable() {
+        let mut list = ExpirationList::new();
+        for idx in 0..10_000 {
+            list.add(idx as i32);
+            list.remove(idx);
+        }
+        for idx in 0..10 {
+            list.add(idx);
+        }
+        list.remove(10_004);
+        list.remove(10_007);
+        list.remove(10_008);
+
+        let mut result: Vec<i32> = Vec::new();
+        for (key, value) in &list {
+            assert_eq!(key as i32 - 10_000, *value);
+            result.push(*value);
+        }
+
+        assert_eq!(result, vec![0, 1, 2, 3, 5, 6, 9]);
+    }
+
+    #[test]
+    fn it_removes_multiple_times() {
+        let mut list = ExpirationList::new();
+        for idx in 0..10 {
+            list.add(idx);
+        }
+        assert_eq!(list.remove(1), Some(1));
+        for _ in 0..10 {
+            assert_eq!(list.remove(1), None);
+        }
+        for idx in 0..10 {
+            list.add(idx);
+        }
+        assert_eq!(list.count, 19);
+    }
+
+    #[test]
+    fn it_reserves_map_capacity_before_shrink() {
+        let mut list = ExpirationList::new();
+        list.reserve_map(20);
+        let map_capacity = list.map.capacity();
+        assert!(map_capacity >= 20);
+
+        for idx in 0..128 {
+            list.add(idx);
+        }
+        // Keep ids 0..20 alive so the shrink migrates exactly 20 items to the map
+        for idx in 20..85 {
+            list.remove(idx);
+        }
+        assert_eq!(list.first_id, 64);
+        assert_eq!(list.map.len(), 20);
+        assert_eq!(list.map.capacity(), map_capacity);
+    }
+
+    #[test]
+    fn it_chunks_the_list() {
+        let mut list = ExpirationList::new();
+        for idx in 0..100 {
+            list.add(idx);
+        }
+        for idx in (0..100).step_by(3) {
+            list.remove(idx);
+        }
+
+        let mut live = Vec::new();
+        for (base_id, slots) in list.list_chunks(8) {
+            assert!(slots.len() <= 8);
+            for (offset, slot) in slots.iter().enumerate() {
+                if let Some(value) = slot {
+                    live.push((base_id + offset, *value));
+                }
+            }
+        }
+
+        let mut expected: Vec<(usize, usize)> =
+            (&list).into_iter().map(|(id, v)| (id, *v)).collect();
+        expected.sort();
+        assert_eq!(live, expected);
+    }
+
+    #[test]
+    fn it_expires_oldest() {
+        let mut list = ExpirationList::new();
+        for idx in 0..10 {
+            list.add(idx * 10);
+        }
+        list.remove(1);
+
+        assert_eq!(list.expire_oldest(3), vec![(0, 0), (2, 20), (3, 30)]);
+        assert_eq!(list.len(), 6);
+        assert_eq!(list.get(4), Some(&40));
+
+        assert_eq!(list.expire_oldest(100).len(), 6);
+        assert!(list.is_empty());
+    }
+
+    #[test]
+    fn it_expires_oldest_from_the_map_first() {
+        let mut list = ExpirationList::new();
+        for idx in 0..64 {
+            list.add(idx);
+        }
+        for idx in 2..35 {
+            list.remove(idx);
+        }
+        assert_eq!(list.map.len(), 2);
+
+        assert_eq!(list.expire_oldest(3), vec![(0, 0), (1, 1), (35, 35)]);
+    }
+
+    #[test]
+    fn it_reports_residence() {
+        let mut list = ExpirationList::new();
+        for idx in 0..64 {
+            list.add(idx);
+        }
+        assert_eq!(list.residence(0), Some(Region::List));
+
+        for idx in 1..34 {
+            list.remove(idx);
+        }
+        assert_eq!(list.residence(0), Some(Region::Map));
+        assert_eq!(list.residence(1), None);
+        assert_eq!(list.residence(40), Some(Region::List));
+        assert_eq!(list.residence(64), None);
+    }
+
+    #[test]
+    fn it_reuses_free_slots() {
+        let mut list = ExpirationList::with_free_list();
+        for idx in 0..10 {
+            list.add(idx);
+        }
+        list.remove(6);
+        list.remove(3);
+        assert_eq!(list.add(100), 3);
+        assert_eq!(list.add(101), 6);
+        assert_eq!(list.add(102), 10);
+        assert_eq!(list.get(3), Some(&100));
+
+        let mut churn = ExpirationList::with_free_list();
+        for idx in 0..100 {
+            churn.add(idx);
+        }
+        let mut id = 0;
+        for step in 0..10_000 {
+            id = (id + 37) % 100;
+            churn.remove(id);
+            assert_eq!(churn.add(step), id);
+        }
+        assert_eq!(churn.len(), 100);
+        assert_eq!(churn.list.len(), 100);
+    }
+
+    #[test]
+    fn it_explains_failed_removes() {
+        let mut list = ExpirationList::new();
+        for idx in 0..10 {
+            list.add(idx);
+        }
+        assert_eq!(list.try_remove(4), Ok(4));
+        assert_eq!(list.try_remove(4), Err(RemoveError::AlreadyRemoved));
+        assert_eq!(list.try_remove(10), Err(RemoveError::NeverIssued));
+
+        let mut list = ExpirationList::with_capacity_and_first_id(4, 5_000);
+        list.add(0);
+        assert_eq!(list.try_remove(10), Err(RemoveError::NeverIssued));
+        assert_eq!(list.try_remove(5_000), Ok(0));
+        assert_eq!(list.try_remove(5_000), Err(RemoveError::AlreadyRemoved));
+    }
+
+    #[test]
+    fn it_yields_live_ranges() {
+        let mut list = ExpirationList::new();
+        for idx in 0..20 {
+            list.add(idx);
+        }
+        for idx in (0..3).chain(8..12).chain(17..20) {
+            list.remove(idx);
+        }
+        assert_eq!(list.live_ranges().collect::<Vec<_>>(), vec![3..8, 12..17]);
+
+        let mut list = ExpirationList::new();
+        for idx in 0..64 {
+            list.add(idx);
+        }
+        for idx in (1..35).filter(|idx| *idx != 5) {
+            list.remove(idx);
+        }
+        assert_eq!(list.map.len(), 2);
+        assert_eq!(
+            list.live_ranges().collect::<Vec<_>>(),
+            vec![0..1, 5..6, 35..64]
+        );
+    }
+
+    #[test]
+    fn it_starts_at_a_base_id_with_capacity() {
+        let mut list = ExpirationList::with_capacity_and_first_id(100, 5_000);
+        let capacity = list.list.capacity();
+        assert!(capacity >= 100);
+
+        for idx in 0..100 {
+            assert_eq!(list.add(idx), 5_000 + idx);
+        }
+        assert_eq!(list.list.capacity(), capacity);
+        assert_eq!(list.get(5_099), Some(&99));
+        assert_eq!(list.get(99), None);
+    }
+
+    #[test]
+    fn it_takes_without_shrinking() {
+        let mut list = ExpirationList::new();
+        for idx in 0..64 {
+            list.add(idx);
+        }
+        for idx in 1..40 {
+            assert_eq!(list.take(idx), Some(idx));
+        }
+        assert_eq!(list.take(1), None);
+        assert_eq!(list.first_id, 0);
+        assert_eq!(list.list.len(), 64);
+        assert_eq!(list.len(), 25);
+
+        list.shrink_now();
+        assert_eq!(list.first_id, 32);
+        assert_eq!(list.get(0), Some(&0));
+        assert_eq!(list.len(), 25);
+    }
+
+    #[test]
+    fn it_resumes_iteration_from_current_id() {
+        let mut list = ExpirationList::new();
+        for idx in 0..64 {
+            list.add(idx);
+        }
+        for idx in (1..45).filter(|idx| idx % 5 != 0) {
+            list.remove(idx);
+        }
+        assert!(!list.map.is_empty());
+
+        let mut iter = list.iter_range(..);
+        let first: Vec<usize> = iter.by_ref().take(5).map(|(id, _)| id).collect();
+        assert_eq!(first, vec![0, 5, 10, 15, 20]);
+        let cursor = iter.current_id();
+        assert_eq!(cursor, 25);
+        drop(iter);
+
+        let rest: Vec<usize> = list.iter_range(cursor..).map(|(id, _)| id).collect();
+        let expected: Vec<usize> = vec![25, 30, 35, 40].into_iter().chain(45..64).collect();
+        assert_eq!(rest, expected);
+        assert_eq!(list.iter_range(10..=30).count(), 5);
+    }
+
+    #[test]
+    fn it_merges_two_lists_in_id_order() {
+        let mut left = ExpirationList::new();
+        let mut right = ExpirationList::new();
+        for idx in 0..6 {
+            left.add(idx);
+            right.add(idx * 10);
+        }
+        right.add(60);
+        left.remove(1);
+        left.remove(2);
+        right.remove(2);
+        right.remove(4);
+
+        let merged: Vec<(usize, MergeSide<&usize>)> = left.merge_iter(&right).collect();
+        assert_eq!(
+            merged,
+            vec![
+                (0, MergeSide::Both(&0, &0)),
+                (1, MergeSide::Right(&10)),
+                (3, MergeSide::Both(&3, &30)),
+                (4, MergeSide::Left(&4)),
+                (5, MergeSide::Both(&5, &50)),
+                (6, MergeSide::Right(&60)),
+            ]
+        );
+    }
+
+    #[test]
+    fn it_adds_an_entry() {
+        let mut list = ExpirationList::new();
+        list.add(String::from("first"));
+        let (id, value) = list.add_entry(String::from("second"));
+        value.push_str(" entry");
+        assert_eq!(id, 1);
+        assert_eq!(list.get(1).map(String::as_str), Some("second entry"));
+    }
+
+    #[test]
+    fn it_notifies_on_grow() {
+        use std::sync::{Arc, Mutex};
+
+        let mut list = ExpirationList::with_capacity_and_first_id(4, 0);
+        let grows = Arc::new(Mutex::new(Vec::new()));
+        let recorded = Arc::clone(&grows);
+        list.on_grow(move |old, new| recorded.lock().unwrap().push((old, new)));
+
+        for idx in 0..4 {
+            list.add(idx);
+        }
+        assert!(grows.lock().unwrap().is_empty());
+
+        list.add(4);
+        let new_capacity = list.list.capacity();
+        assert_eq!(*grows.lock().unwrap(), vec![(4, new_capacity)]);
+    }
+
+    #[test]
+    fn it_skips_duplicate_ids_in_strict_iteration() {
+        let mut map = FnvHashMap::default();
+        map.insert(1, 10);
+        map.insert(5, 50);
+        map.insert(6, 60);
+        let list = ExpirationList::from_parts(4, vec![Some(4), Some(5), None], map);
+
+        let mut iter = list.iter_strict();
+        let mut result: Vec<(usize, i32)> = iter.by_ref().map(|(id, v)| (id, *v)).collect();
+        result.sort();
+        assert_eq!(result, vec![(1, 10), (4, 4), (5, 5), (6, 60)]);
+        assert_eq!(iter.anomalies(), 1);
+        assert_eq!(list.stats().duplicate_ids, 1);
+    }
+
+    #[test]
+    fn it_resizes_with_new_items() {
+        let mut list = ExpirationList::new();
+        let mut next = 0;
+        list.resize_with(10, || {
+            next += 1;
+            next
+        });
+        assert_eq!(list.len(), 10);
+        for id in 0..10 {
+            assert_eq!(list.get(id), Some(&(id + 1)));
+        }
+
+        list.remove(3);
+        list.resize_with(10, || 100);
+        assert_eq!(list.len(), 10);
+        assert_eq!(list.get(10), Some(&100));
+
+        list.resize_with(5, || 0);
+        assert_eq!(list.len(), 10);
+    }
+
+    #[test]
+    fn it_iterates_excluding_ids() {
+        let mut list = ExpirationList::new();
+        for idx in 0..10 {
+            list.add(idx);
+        }
+        let exclude: HashSet<usize> = [2, 5, 7, 20].into_iter().collect();
+        let mut ids: Vec<usize> = list.iter_excluding(&exclude).map(|(id, _)| id).collect();
+        ids.sort();
+        assert_eq!(ids, vec![0, 1, 3, 4, 6, 8, 9]);
+    }
+
+    #[test]
+    fn it_validates_inner_parts() {
+        let mut list = ExpirationList::new();
+        for idx in 0..100 {
+            list.add(idx);
+        }
+        for idx in 0..80 {
+            list.remove(idx * 13 % 100);
+        }
+        assert_eq!(list.validate(), Ok(()));
+
+        let mut map = FnvHashMap::default();
+        map.insert(3, 3);
+        let list = ExpirationList::from_parts(2, vec![Some(2), None], map);
+        assert_eq!(list.validate(), Err(ValidationError::MapIdInList(3)));
+    }
+
+    #[cfg(feature = "debug_invariants")]
+    #[test]
+    fn it_keeps_invariants_over_random_operations() {
+        let mut list = ExpirationList::new();
+        let mut ids = Vec::new();
+        let mut seed: u64 = 0x2545_f491_4f6c_dd1d;
+        for step in 0..50_000 {
+            seed = seed
+                .wrapping_mul(6_364_136_223_846_793_005)
+                .wrapping_add(1_442_695_040_888_963_407);
+            let roll = (seed >> 33) as usize;
+            if ids.is_empty() || roll % 5 < 2 {
+                ids.push(list.add(step));
+            } else {
+                let id = ids.swap_remove(roll % ids.len());
+                assert!(list.remove(id).is_some());
+            }
+        }
+        assert_eq!(list.len(), ids.len());
+    }
+
+    #[test]
+    fn it_pops_oldest_conditionally() {
+        let mut list = ExpirationList::new();
+        for deadline in [5, 10, 15] {
+            list.add(deadline);
+        }
+        list.remove(0);
+
+        assert_eq!(list.pop_oldest_if(|deadline| *deadline <= 8), None);
+        assert_eq!(list.len(), 2);
+        assert_eq!(
+            list.pop_oldest_if(|deadline| *deadline <= 12),
+            Some((1, 10))
+        );
+        assert_eq!(list.get(1), None);
+        assert_eq!(list.len(), 1);
+    }
+
+    #[test]
+    fn it_creates_from_fn() {
+        let list = ExpirationList::from_fn(100, |idx| idx * 2);
+        assert_eq!(list.len(), 100);
+        for id in 0..100 {
+            assert_eq!(list.get(id), Some(&(id * 2)));
+        }
+        assert_eq!(list.get(100), None);
+    }
+
+    #[test]
+    fn it_iterates_fairly_between_regions() {
+        let mut list = ExpirationList::new();
+        for idx in 0..64 {
+            list.add(idx);
+        }
+        for idx in 3..36 {
+            list.remove(idx);
+        }
+        assert_eq!(list.map.len(), 3);
+
+        let regions: Vec<Region> = list
+            .iter_fair()
+            .map(|(id, _)| list.residence(id).unwrap())
+            .collect();
+        assert_eq!(
+            regions[..7],
+            [
+                Region::List,
+                Region::Map,
+                Region::List,
+                Region::Map,
+                Region::List,
+                Region::Map,
+                Region::List
+            ]
+        );
+        assert!(regions[7..].iter().all(|region| *region == Region::List));
+        assert_eq!(regions.len(), list.len());
+    }
+
+    #[test]
+    fn it_gets_a_subset() {
+        let mut list = ExpirationList::new();
+        for idx in 0..10 {
+            list.add(idx * 10);
+        }
+        list.remove(4);
+
+        let subset = list.get_subset(&[1, 4, 7, 7, 42]);
+        assert_eq!(subset.len(), 2);
+        assert_eq!(subset.get(&1), Some(&&10));
+        assert_eq!(subset.get(&7), Some(&&70));
+        assert!(!subset.contains_key(&4));
+    }
+
+    #[test]
+    fn it_consumes_in_id_order() {
+        let mut list = ExpirationList::new();
+        for idx in 0..64 {
+            list.add(idx.to_string());
+        }
+        for idx in (0..48).filter(|idx| idx % 4 != 0) {
+            list.remove(idx);
+        }
+        assert!(!list.map.is_empty());
+        let len = list.len();
+
+        let items: Vec<(usize, String)> = list.into_iter_sorted().collect();
+        assert_eq!(items.len(), len);
+        assert!(items.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        assert!(items.iter().all(|(id, value)| *value == id.to_string()));
+    }
+
+    #[test]
+    fn it_tracks_reallocations_on_add() {
+        let mut list = ExpirationList::with_capacity_and_first_id(8, 0);
+        for idx in 0..8 {
+            assert_eq!(list.add_tracked(idx), (idx, false));
+        }
+        assert_eq!(list.add_tracked(8), (8, true));
+        assert_eq!(list.add_tracked(9), (9, false));
+    }
+
+    #[test]
+    fn it_clears_conditionally() {
+        let mut list = ExpirationList::new();
+        for idx in 0..500 {
+            list.add(idx);
+        }
+        assert!(!list.clear_if(|list| list.len() > 1000));
+        assert_eq!(list.len(), 500);
+
+        for idx in 500..1500 {
+            list.add(idx);
+        }
+        assert!(list.clear_if(|list| list.len() > 1000));
+        assert!(list.is_empty());
+        assert_eq!(list.get(0), None);
+        assert_eq!(list.add(0), 1500);
+    }
+
+    #[test]
+    fn it_iterates_with_age() {
+        let mut list = ExpirationList::new();
+        for idx in 0..10 {
+            list.add(idx);
+        }
+        list.remove(9);
+        list.remove(4);
+
+        let mut ages: Vec<(usize, usize)> =
+            list.iter_with_age().map(|(id, _, age)| (id, age)).collect();
+        ages.sort_by_key(|(_, age)| *age);
+        assert_eq!(ages[0], (8, 0));
+        assert!(ages.windows(2).all(|pair| pair[0].0 > pair[1].0));
+        assert_eq!(ages.last(), Some(&(0, 8)));
+    }
+
+    #[test]
+    fn it_compacts_the_map_into_the_list() {
+        let mut list = ExpirationList::new();
+        for idx in 0..128 {
+            list.add(idx);
+        }
+        for idx in 20..85 {
+            list.remove(idx);
+        }
+        assert_eq!(list.map.len(), 20);
+
+        list.compact();
+        assert!(list.map.is_empty());
+        assert_eq!(list.first_id, 0);
+        assert_eq!(list.len(), 63);
+        assert_eq!(list.validate(), Ok(()));
+        for idx in (0..20).chain(85..128) {
+            assert_eq!(list.get(idx), Some(&idx));
+        }
+        assert_eq!(list.get(50), None);
+    }
+
+    #[test]
+    fn it_caps_the_map_ratio() {
+        let mut list = ExpirationList::with_map_ratio_cap(0.05);
+        for idx in 0..128 {
+            list.add(idx);
+        }
+        for idx in 20..85 {
+            list.remove(idx);
+            assert!(list.map.len() as f64 <= 0.05 * list.list.len() as f64);
+        }
+        assert!(list.first_id > 0);
+        assert_eq!(list.len(), 63);
+        for idx in (0..20).chain(85..128) {
+            assert_eq!(list.get(idx), Some(&idx));
+        }
+
+        // The shrink is capped right away, and compacting leaves the list dense
+        let map = (50..100).map(|idx| (idx, idx)).collect();
+        let list = (100..200).map(|idx| (idx >= 160).then_some(idx)).collect();
+        let mut list = ExpirationList::from_parts(100, list, map);
+        list.map_ratio_cap = Some(0.5);
+        list.shrink_now();
+        assert!(list.map.is_empty());
+        assert_eq!(list.first_id, 50);
+        assert_eq!(list.len(), 90);
+    }
+
+    #[test]
+    fn it_still_shrinks_with_a_map_ratio_cap_and_one_old_item() {
+        let mut list = ExpirationList::with_map_ratio_cap(0.01);
+        for idx in 0..10_000 {
+            list.add(idx);
+        }
+        for idx in 1..10_000 {
+            list.remove(idx);
+        }
+        assert!(list.list.len() < 1_000);
+
+        for idx in 0..2_000 {
+            let id = list.add(idx);
+            list.remove(id);
+        }
+        assert!(list.list.len() < 1_000);
+        assert_eq!(list.get(0), Some(&0));
+        assert_eq!(list.len(), 1);
+    }
+
+    #[test]
+    fn it_tries_to_reserve_and_add() {
+        let mut list = ExpirationList::new();
+        assert!(list.try_reserve_exact(100).is_ok());
+        assert!(list.list.capacity() >= 100);
+        assert_eq!(list.try_add_iter(0..100), Ok(0..100));
+        assert_eq!(list.get(99), Some(&99));
+
+        assert!(list.try_reserve_exact(usize::MAX).is_err());
+
+        // An iterator that claims to be larger than any allocation can be
+        struct Endless;
+        impl Iterator for Endless {
+            type Item = usize;
+            fn next(&mut self) -> Option<usize> {
+                Some(0)
+            }
+            fn size_hint(&self) -> (usize, Option<usize>) {
+                (usize::MAX, None)
+            }
+        }
+        let error = list.try_add_iter(Endless).unwrap_err();
+        assert_eq!(error.added, 0);
+        assert_eq!(list.len(), 100);
+
+        // An iterator that under-reports its size fails part way once growing is refused
+        struct Unhinted(u64);
+        impl Iterator for Unhinted {
+            type Item = u64;
+            fn next(&mut self) -> Option<u64> {
+                self.0 += 1;
+                return Some(self.0);
+            }
+        }
+        let mut list = ExpirationList::new();
+        assert!(list.try_reserve_exact(4).is_ok());
+        let limit = list.list.capacity() * std::mem::size_of::<Option<u64>>();
+        ALLOC_LIMIT.with(|cell| cell.set(limit));
+        let result = list.try_add_iter(Unhinted(0));
+        ALLOC_LIMIT.with(|cell| cell.set(usize::MAX));
+        let error = result.unwrap_err();
+        assert_eq!(error.added, list.list.capacity());
+        assert_eq!(list.len(), error.added);
+        assert_eq!(list.get(0), Some(&1));
+        assert_eq!(list.validate(), Ok(()));
+    }
+
+    #[test]
+    fn it_peeks_with_a_cursor() {
+        let mut list = ExpirationList::new();
+        for idx in 0..64 {
+            list.add(idx);
+        }
+        for idx in (1..40).filter(|idx| *idx != 7) {
+            list.remove(idx);
+        }
+        assert_eq!(list.map.len(), 2);
+
+        let mut cursor = list.cursor();
+        assert_eq!(cursor.current(), None);
+        assert_eq!(cursor.peek_next(), Some((0, &0)));
+        cursor.move_next();
+        assert_eq!(cursor.current(), Some((0, &0)));
+
+        for _ in 0..3 {
+            let peeked = cursor.peek_next();
+            assert_eq!(cursor.peek_next(), peeked);
+            cursor.move_next();
+            assert_eq!(cursor.current(), peeked);
+        }
+        assert_eq!(cursor.current(), Some((41, &41)));
+
+        while cursor.peek_next().is_some() {
+            cursor.move_next();
+        }
+        assert_eq!(cursor.current(), Some((63, &63)));
+        cursor.move_next();
+        assert_eq!(cursor.current(), None);
+    }
+
+    #[test]
+    fn it_resets_ids() {
+        let mut list = ExpirationList::new();
+        for idx in 0..64 {
+            list.add(idx);
+        }
+        for idx in 1..40 {
+            list.remove(idx);
+        }
+        list.pin_range(50..60);
+        list.auto_compact = Some(100);
+        assert_eq!(list.get(0), Some(&0));
+        assert_eq!(*list.slow_hits.get_mut(), 1);
+
+        let items = list.reset();
+        assert!(list.pinned.is_empty());
+        assert_eq!(*list.slow_hits.get_mut(), 0);
+        assert_eq!(items.len(), 25);
+        assert_eq!(items[0], (0, 0));
+        assert_eq!(items[1], (40, 40));
+        assert!(list.is_empty());
+        assert_eq!(list.get(40), None);
+        assert_eq!(list.add(7), 0);
+        assert_eq!(list.get(0), Some(&7));
+    }
+
+    #[test]
+    fn it_yields_runs_of_equal_items() {
+        let mut list = ExpirationList::new();
+        for value in ["a", "a", "b", "a", "x", "a", "a"] {
+            list.add(value);
+        }
+        list.remove(4);
+
+        let runs: Vec<(usize, &str, usize)> = list
+            .runs()
+            .map(|(id, value, length)| (id, *value, length))
+            .collect();
+        assert_eq!(
+            runs,
+            vec![(0, "a", 2), (2, "b", 1), (3, "a", 1), (5, "a", 2)]
+        );
+    }
+
+    #[test]
+    fn it_gets_mut_or_panics_with_context() {
+        let mut list = ExpirationList::new();
+        list.add(1);
+        *list.get_mut_expect(0, "item should exist") += 1;
+        assert_eq!(list.get(0), Some(&2));
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "session lookup: no item with id 3 (the item was already removed, first_id 0, next_id 5)"
+    )]
+    fn it_panics_with_context_on_missing_get_mut() {
+        let mut list = ExpirationList::new();
+        for idx in 0..5 {
+            list.add(idx);
+        }
+        list.remove(3);
+        list.get_mut_expect(3, "session lookup");
+    }
+
+    #[test]
+    fn it_limits_shrinks_with_hysteresis() {
+        fn count_oscillating_shrinks(mut list: ExpirationList<usize>) -> usize {
+            for idx in 0..4096 {
+                list.add(idx);
+            }
+            for idx in (1..4096).step_by(2) {
+                list.remove(idx);
+            }
+
+            let mut shrinks = 0;
+            for _ in 0..1000 {
+                let first_id = list.first_id;
+                let newest_id = list.newest_id().unwrap();
+                list.remove(newest_id);
+                list.add(0);
+                if list.first_id != first_id {
+                    shrinks += 1;
+                }
+            }
+            return shrinks;
+        }
+
+        let without = count_oscillating_shrinks(ExpirationList::new());
+        let with = count_oscillating_shrinks(ExpirationList::with_hysteresis(0.4, 0.6));
+        assert!(without >= 50);
+        assert!(with * 10 < without);
+    }
+
+    #[test]
+    fn it_iterates_matching_ids() {
+        // Built from parts so the fixture does not pay for a million removals
+        let map = (0..500_000).step_by(2).map(|idx| (idx, idx)).collect();
+        let list = (500_000..1_000_000).map(Some).collect();
+        let mut list = ExpirationList::from_parts(500_000, list, map);
+        list.remove(900_000);
+
+        let ids = [999_999, 11, 10, 900_000, 700_000, 10, 2_000_000];
+        let found: Vec<(usize, usize)> = list.iter_matching(&ids).map(|(id, v)| (id, *v)).collect();
+        assert_eq!(
+            found,
+            vec![(10, 10), (700_000, 700_000), (999_999, 999_999)]
+        );
+    }
+
+    #[test]
+    fn it_keeps_shrinking_with_hysteresis_when_drained() {
+        fn drain_to_ten(mut list: ExpirationList<usize>) -> usize {
+            for idx in 0..8192 {
+                list.add(idx);
+            }
+            let mut ids: Vec<usize> = (0..8192).collect();
+            let mut seed: u64 = 0x9e37_79b9_7f4a_7c15;
+            while ids.len() > 10 {
+                seed = seed
+                    .wrapping_mul(6_364_136_223_846_793_005)
+                    .wrapping_add(1_442_695_040_888_963_407);
+                let id = ids.swap_remove((seed >> 33) as usize % ids.len());
+                list.remove(id);
+            }
+            assert_eq!(list.len(), 10);
+            return list.list.len();
+        }
+
+        let without = drain_to_ten(ExpirationList::new());
+        let with = drain_to_ten(ExpirationList::with_hysteresis(0.4, 0.6));
+        assert!(with <= without * 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "hysteresis watermarks")]
+    fn it_rejects_inverted_hysteresis() {
+        ExpirationList::<usize>::with_hysteresis(0.6, 0.4);
+    }
+
+    #[cfg(feature = "serde_support")]
+    #[test]
+    fn it_deserializes_lists_without_tuning_fields() {
+        let json = r#"{"first_id":0,"count":2,"list":[1,2],"map":{}}"#;
+        let mut list: ExpirationList<i32> = serde_json::from_str(json).unwrap();
+        assert_eq!(list.get(1), Some(&2));
+        assert!(list.shrink_armed);
+        assert_eq!(list.add(3), 2);
+    }
+
+    #[test]
+    fn it_sets_values_by_id() {
+        let mut list = ExpirationList::new();
+        for idx in 0..64 {
+            list.add(idx);
+        }
+        for idx in 0..40 {
+            list.remove(idx);
+        }
+        assert!(list.first_id > 0);
+
+        // Over an existing item
+        assert_eq!(list.set(50, 500), Some(50));
+        assert_eq!(list.get(50), Some(&500));
+
+        // Into a hole in the `Vec` and below it
+        assert_eq!(list.set(39, 390), None);
+        assert_eq!(list.get(39), Some(&390));
+        assert_eq!(list.set(0, 1), None);
+        assert_eq!(list.get(0), Some(&1));
+
+        // Far beyond the end of the `Vec`
+        assert_eq!(list.set(100, 1000), None);
+        assert_eq!(list.get(100), Some(&1000));
+        assert_eq!(list.get(99), None);
+        assert_eq!(list.len(), 27);
+        assert_eq!(list.add(101), 101);
+        assert_eq!(list.validate(), Ok(()));
+    }
+
+    #[test]
+    fn it_lends_mutable_items() {
+        let mut list = ExpirationList::new();
+        for idx in 0..64 {
+            list.add(idx);
+        }
+        for idx in (0..64).filter(|idx| idx % 4 != 0) {
+            list.remove(idx);
+        }
+        assert!(!list.map.is_empty());
+
+        let mut ids = Vec::new();
+        let mut iter = list.lending_iter_mut();
+        while let Some((id, value)) = iter.next() {
+            *value *= 10;
+            ids.push(id);
+        }
+        assert_eq!(ids, (0..64).step_by(4).collect::<Vec<_>>());
+        for id in ids {
+            assert_eq!(list.get(id), Some(&(id * 10)));
+        }
+    }
+
+    #[test]
+    fn it_compacts_after_many_map_lookups() {
+        let mut list = ExpirationList::with_auto_compact(8);
+        for idx in 0..64 {
+            list.add(idx);
+        }
+        for idx in 31..64 {
+            list.remove(idx);
+        }
+        assert_eq!(list.map.len(), 31);
+
+        for idx in 0..10 {
+            list.add(idx);
+        }
+        for id in 0..7 {
+            assert_eq!(list.get(id), Some(&id));
+        }
+        // A lookup that does not hit the `HashMap` starts the count over
+        assert_eq!(list.get(64), Some(&0));
+        for id in 0..8 {
+            assert_eq!(list.get(id), Some(&id));
+        }
+        assert_eq!(list.map.len(), 31);
+        assert_eq!(list.get_mut(8), Some(&mut 8));
+        assert_eq!(list.map.len(), 0);
+        assert_eq!(list.len(), 41);
+        assert_eq!(list.get(30), Some(&30));
+    }
+
+    #[test]
+    fn it_iterates_a_shared_snapshot() {
+        let list = sparse_list();
+
+        let shared = Arc::new(list);
+        let iter = ExpirationList::snapshot_iter(Arc::clone(&shared));
+        drop(shared);
+        let items: Vec<(usize, usize)> = iter.collect();
+        assert_eq!(
+            items,
+            (0..64).step_by(3).map(|id| (id, id)).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn it_reports_which_remove_shrank() {
+        let mut list = ExpirationList::new();
+        for idx in 0..64 {
+            list.add(idx);
+        }
+        let shrinks: Vec<usize> = (0..40)
+            .filter(|id| list.remove_profiled(*id).unwrap().1)
+            .collect();
+        assert_eq!(shrinks, vec![32]);
+        assert_eq!(list.remove_profiled(0), None);
+    }
+
+    #[test]
+    fn it_extends_with_ids() {
+        let mut list = ExpirationList::new();
+        for idx in 0..4 {
+            list.add(idx);
+        }
+        list.remove(1);
+        list.extend(vec![(1, 10), (2, 20), (9, 90), (2, 21)]);
+        assert_eq!(list.get(0), Some(&0));
+        assert_eq!(list.get(1), Some(&10));
+        assert_eq!(list.get(2), Some(&21));
+        assert_eq!(list.get(3), Some(&3));
+        assert_eq!(list.get(9), Some(&90));
+        assert_eq!(list.len(), 5);
+        assert_eq!(list.add(10), 10);
+    }
+
+    #[test]
+    fn it_estimates_the_capacity_for_churn() {
+        for n in [1, 10, 100, 1000] {
+            let capacity = ExpirationList::<usize>::capacity_for(n);
+            assert!(capacity >= n);
+            assert!(capacity <= 2 * n + 33);
+
+            let mut list = ExpirationList::with_capacity_and_first_id(capacity, 0);
+            for idx in 0..n {
+                list.add(idx);
+            }
+            for idx in 0..10 * n {
+                list.remove(idx);
+                list.add(idx);
+                assert!(list.list.len() <= capacity);
+            }
+        }
+    }
+
+    #[test]
+    fn it_iterates_by_stride() {
+        let mut list = ExpirationList::with_capacity_and_first_id(64, 3);
+        for idx in 3..67 {
+            list.add(idx);
+        }
+        for idx in (3..67).filter(|idx| idx % 5 != 0 && *idx != 61) {
+            list.remove(idx);
+        }
+        assert!(!list.map.is_empty() && list.first_id % 4 != 0);
+
+        let mut all_ids = Vec::new();
+        for residue in 0..4 {
+            for (id, value) in list.iter_stride(4, residue) {
+                assert_eq!(id % 4, residue);
+                assert_eq!(*value, id);
+                all_ids.push(id);
+            }
+        }
+        all_ids.sort_unstable();
+        let mut live_ids: Vec<usize> = (&list).into_iter().map(|(id, _)| id).collect();
+        live_ids.sort_unstable();
+        assert_eq!(all_ids, live_ids);
+    }
+
+    #[test]
+    fn it_spills_instead_of_using_the_map() {
+        use std::sync::Mutex;
+
+        let spilled = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&spilled);
+        let mut list =
+            ExpirationList::with_spill(move |id, value| sink.lock().unwrap().push((id, value)));
+        for idx in 0..64 {
+            list.add(idx);
+        }
+        for idx in (0..64).filter(|idx| idx % 4 != 0) {
+            list.remove(idx);
+        }
+
+        let spilled = spilled.lock().unwrap();
+        assert!(!spilled.is_empty());
+        assert!(list.map.is_empty());
+        for (id, value) in spilled.iter() {
+            assert_eq!(id, value);
+            assert_eq!(list.get(*id), None);
+        }
+        assert_eq!(list.len() + spilled.len(), 16);
+    }
+
+    #[test]
+    fn it_iterates_mutably_in_reverse() {
+        let mut list = sparse_list();
+
+        let mut visited = Vec::new();
+        for (position, (id, value)) in list.iter_mut_rev().enumerate() {
+            *value += position * 1000;
+            visited.push(id);
+        }
+        assert_eq!(visited.first(), Some(&63));
+        let expected: Vec<usize> = (0..64).step_by(3).rev().collect();
+        assert_eq!(visited, expected);
+        for (position, id) in expected.into_iter().enumerate() {
+            assert_eq!(list.get(id), Some(&(id + position * 1000)));
+        }
+    }
+
+    #[test]
+    fn it_polls_the_oldest_item() {
+        let mut list = ExpirationList::new();
+        assert_eq!(list.poll_oldest(), Poll::Pending);
+        for idx in 0..64 {
+            list.add(idx);
+        }
+        for idx in 0..40 {
+            list.remove(idx * 7 % 64);
+        }
+        let oldest = (&list).into_iter().map(|(id, _)| id).min().unwrap();
+        assert_eq!(list.poll_oldest(), Poll::Ready((oldest, oldest)));
+        while list.poll_oldest().is_ready() {}
+        assert!(list.is_empty());
+        assert_eq!(list.poll_oldest(), Poll::Pending);
+    }
+
+    #[test]
+    fn it_recomputes_ids_after_shrinks() {
+        let mut list = ExpirationList::new();
+        for round in 0..4 {
+            for idx in 0..64 {
+                list.add(idx);
+            }
+            let first_id = list.first_id;
+            for idx in (first_id..list.next_id()).filter(|id| id % 5 != round) {
+                list.remove(idx);
+            }
+        }
+        assert!(list.first_id > 0 && !list.map.is_empty());
+        assert_eq!(list.debug_iter().count(), list.len());
+        for (reported_id, recomputed_id, _) in list.debug_iter() {
+            assert_eq!(reported_id, recomputed_id);
+        }
+    }
+
+    #[test]
+    fn it_appends_from_a_slice() {
+        let mut list = ExpirationList::new();
+        list.add(String::from("first"));
+        let values = vec![String::from("a"), String::from("b"), String::from("c")];
+        let ids = list.append_from_slice(&values);
+        assert_eq!(ids, 1..4);
+        for (id, value) in ids.zip(&values) {
+            assert_eq!(list.get(id), Some(value));
+        }
+        assert_eq!(list.len(), 4);
+        assert_eq!(list.append_from_slice(&[]), 4..4);
+    }
+
+    #[test]
+    fn it_tries_each_item_until_an_error() {
+        let mut list = ExpirationList::new();
+        for idx in 0..64 {
+            list.add(idx);
+        }
+        for idx in 0..40 {
+            list.remove(idx * 7 % 64);
+        }
+        assert!(!list.map.is_empty());
+        assert_eq!(list.try_for_each(|_, _| Ok::<_, ()>(())), Ok(()));
+
+        let bad_id = *list.map.keys().next().unwrap();
+        let mut seen = 0;
+        let result = list.try_for_each(|id, _| {
+            seen += 1;
+            if id == bad_id { Err("bad") } else { Ok(()) }
+        });
+        assert_eq!(result, Err((bad_id, "bad")));
+        assert_eq!(seen, 1);
+
+        let last_id = list.newest_id().unwrap();
+        let result = list.try_for_each(|id, _| if id == last_id { Err("last") } else { Ok(()) });
+        assert_eq!(result, Err((last_id, "last")));
+    }
+
+    #[test]
+    fn it_checks_contains_after_a_shrink() {
+        let mut list = ExpirationList::new();
+        for idx in 0..1024 {
+            list.add(idx);
+        }
+        for idx in 0..513 {
+            list.remove(idx);
+        }
+        assert_eq!(list.first_id, 512);
+
+        assert!(list.contains(600));
+        assert!(!list.contains(100));
+        assert!(!list.contains(512));
+        assert!(list.contains(1023));
+        assert!(!list.contains(1024));
+    }
+
+    #[test]
+    fn it_compacts_to_a_capacity() {
+        let mut list = sparse_list();
+
+        assert!(!list.compact_to(10));
+        assert!(list.map.is_empty());
+        assert!(list.compact_to(80));
+        assert_eq!(list.capacity(), 80);
+        assert!(list.compact_to(list.list.len()));
+        assert_eq!(list.capacity(), list.list.len());
+        assert_eq!(list.get(63), Some(&63));
+        assert_eq!(list.len(), 22);
+    }
+
+    #[test]
+    fn it_keeps_len_across_a_shrink() {
+        let mut list = ExpirationList::new();
+        assert!(list.is_empty());
+        for idx in 0..64 {
+            list.add(idx);
+        }
+        assert_eq!(list.len(), 64);
+        for idx in (0..64).filter(|idx| idx % 4 != 0) {
+            list.remove(idx);
+        }
+        assert!(!list.map.is_empty());
+        assert_eq!(list.len(), 16);
+        assert!(!list.is_empty());
+        for idx in (0..64).step_by(4) {
+            list.remove(idx);
+        }
+        assert_eq!(list.len(), 0);
+        assert!(list.is_empty());
+    }
+
+    #[test]
+    fn it_partitions_items() {
+        let list = sparse_list();
+        let (low, high) = list.partition_collect(|value| *value < 32);
+        assert!(low.iter().all(|(_, value)| **value < 32));
+        assert!(high.iter().all(|(_, value)| **value >= 32));
+        let mut ids: Vec<usize> = low.iter().chain(&high).map(|(id, _)| *id).collect();
+        ids.sort_unstable();
+        assert_eq!(ids, (0..64).step_by(3).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn it_mutates_through_iter_mut() {
+        let mut list = sparse_list();
+
+        for (id, value) in list.iter_mut() {
+            *value += id * 100;
+        }
+        for id in (0..64).step_by(3) {
+            assert_eq!(list.get(id), Some(&(id * 101)));
+        }
+        assert_eq!(list.iter_mut().count(), list.len());
+    }
+
+    #[test]
+    fn it_repairs_inconsistent_parts() {
+        let mut map = FnvHashMap::default();
+        map.insert(0, 0);
+        map.insert(2, 20);
+        map.insert(3, 30);
+        map.insert(6, 60);
+        let mut list = ExpirationList::from_parts(2, vec![Some(2), None], map);
+        assert!(list.validate().is_err());
+
+        assert_eq!(list.repair(), 3);
+        assert_eq!(list.validate(), Ok(()));
+        assert_eq!(list.get(0), Some(&0));
+        assert_eq!(list.get(2), Some(&2));
+        assert_eq!(list.get(3), Some(&30));
+        assert_eq!(list.get(6), Some(&60));
+        assert_eq!(list.len(), 4);
+        assert_eq!(list.repair(), 0);
+    }
+
+    #[test]
+    fn it_iterates_by_value() {
+        // Owned items come out of both the `HashMap` and the `Vec`, skipping removed slots
+        let mut list = ExpirationList::new();
+        for idx in 0..40 {
+            list.add(idx.to_string());
+        }
+        for idx in (0..30).filter(|idx| *idx != 5 && *idx != 20) {
+            list.remove(idx);
+        }
+        list.remove(35);
+        assert!(list.map.contains_key(&5) && list.first_id > 5);
+
+        let mut by_ref: Vec<(usize, String)> = (&list)
+            .into_iter()
+            .map(|(id, value)| (id, value.clone()))
+            .collect();
+        let mut by_value: Vec<(usize, String)> = list.into_iter().collect();
+        by_ref.sort_unstable();
+        by_value.sort_unstable();
+        assert_eq!(by_value, by_ref);
+        assert!(by_value.iter().all(|(id, value)| id.to_string() == *value));
+        let ids: Vec<usize> = by_value.into_iter().map(|(id, _)| id).collect();
+        let expected: Vec<usize> = [5, 20]
+            .into_iter()
+            .chain(30..40)
+            .filter(|id| *id != 35)
+            .collect();
+        assert_eq!(ids, expected);
+    }
+
+    #[test]
+    fn it_exposes_list_slots_through_a_pointer() {
+        let list = sparse_list();
+        let (ptr, len, first_id) = list.list_as_ptr();
+        assert!(first_id > 0);
+        assert_eq!(len, list.list.len());
+        for idx in 0..len {
+            // SAFETY: `idx` is within the `len` slots and the list is not modified meanwhile
+            let slot = unsafe { *ptr.add(idx) };
+            assert_eq!(slot.as_ref(), list.get(first_id + idx));
+        }
+    }
+
+    #[test]
+    fn it_clears_without_reusing_ids() {
+        let mut list = sparse_list();
+        let old_ids: Vec<usize> = (&list).into_iter().map(|(id, _)| id).collect();
+        let capacity = list.list.capacity();
+
+        list.clear();
+        assert!(list.is_empty());
+        assert_eq!(list.list.capacity(), capacity);
+        for id in &old_ids {
+            assert_eq!(list.get(*id), None);
+        }
+        let new_id = list.add(100);
+        assert!(old_ids.iter().all(|id| *id < new_id));
+        assert_eq!(new_id, 64);
+    }
+
+    #[test]
+    fn it_uses_a_custom_hasher() {
+        use std::collections::hash_map::RandomState;
+
+        let mut list: ExpirationList<usize, RandomState> =
+            ExpirationList::with_hasher(RandomState::new());
+        for idx in 0..64 {
+            assert_eq!(list.add(idx), idx);
+        }
+        for idx in (0..64).filter(|idx| idx % 3 != 0) {
+            assert_eq!(list.remove(idx), Some(idx));
+        }
+        assert!(!list.map.is_empty());
+        for idx in 0..64 {
+            assert_eq!(list.get(idx), (idx % 3 == 0).then_some(&idx));
+        }
+        assert_eq!(list.len(), 22);
+        assert_eq!(list.validate(), Ok(()));
+    }
+
+    #[test]
+    fn it_diffs_against_a_previous_list() {
+        let build = sparse_list;
+        let old = build();
+        let mut new = build();
+        new.remove(0);
+        new.remove(63);
+        *new.get_mut(3).unwrap() = 300;
+        *new.get_mut(60).unwrap() = 600;
+        new.add(64);
+
+        let changes: Vec<Change<'_, usize>> = new.diff(&old).collect();
+        assert_eq!(
+            changes,
+            vec![
+                Change::Removed(0),
+                Change::Modified(3, &300),
+                Change::Modified(60, &600),
+                Change::Removed(63),
+                Change::Added(64, &64),
+            ]
+        );
+        let mut synced = build();
+        for change in changes {
+            match change {
+                Change::Added(id, value) | Change::Modified(id, value) => {
+                    synced.set(id, *value);
+                }
+                Change::Removed(id) => {
+                    synced.remove(id);
+                }
+            }
+        }
+        assert_eq!(
+            synced.into_iter_sorted().collect::<Vec<_>>(),
+            new.into_iter_sorted().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn it_retains_matching_items() {
+        let mut list = ExpirationList::new();
+        for idx in 0..1000 {
+            list.add(idx);
+        }
+        list.retain(|_, value| *value % 2 == 0);
+        assert_eq!(list.len(), 500);
+        for id in 0..1000 {
+            assert_eq!(list.get(id), (id % 2 == 0).then_some(&id));
+        }
+
+        list.retain(|id, value| {
+            *value += id;
+            *value % 8 == 0
+        });
+        assert_eq!(list.len(), 250);
+        assert!(list.first_id > 0);
+        for id in (0..1000).step_by(4) {
+            assert_eq!(list.get(id), Some(&(id * 2)));
+        }
+        assert_eq!(list.validate(), Ok(()));
+    }
+
+    #[test]
+    fn it_keeps_ids_ordered_after_random_retains() {
+        let mut list = ExpirationList::new();
+        for idx in 0..10_000 {
+            list.add(idx);
+        }
+        let mut expected: Vec<usize> = (0..10_000).collect();
+        let mut seed: u64 = 0x9e37_79b9_7f4a_7c15;
+        for _ in 0..4 {
+            seed = seed
+                .wrapping_mul(6_364_136_223_846_793_005)
+                .wrapping_add(1_442_695_040_888_963_407);
+            let keep =
+                |id: usize| (id as u64 ^ seed).wrapping_mul(0xff51_afd7_ed55_8ccd) >> 63 == 0;
+            list.retain(|id, _| keep(id));
+            expected.retain(|id| keep(*id));
+
+            assert_eq!(list.validate(), Ok(()));
+            let ids: Vec<usize> = list.iter_range(..).map(|(id, _)| id).collect();
+            assert_eq!(ids, expected);
+        }
+        assert!(!list.map.is_empty() && list.first_id > 0);
+        let items: Vec<(usize, usize)> = list.into_iter_sorted().collect();
+        assert!(items.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        assert!(items.iter().all(|(id, value)| id == value));
+        assert_eq!(items.len(), expected.len());
+    }
+
+    #[test]
+    fn it_applies_a_diff() {
+        let build = || {
+            let mut list = ExpirationList::new();
+            for idx in 0..200 {
+                list.add(idx.to_string());
+            }
+            for idx in (0..200).filter(|idx| idx % 4 != 0) {
+                list.remove(idx);
+            }
+            return list;
+        };
+        let old = build();
+        let mut new = build();
+        new.retain(|id, value| {
+            value.push('!');
+            id % 3 != 0
+        });
+        new.set(1, String::from("one"));
+        for idx in 0..50 {
+            new.add(idx.to_string());
+        }
+
+        let mut synced = build();
+        synced.apply_diff(new.diff(&old));
+        assert_eq!(synced.len(), new.len());
+        assert_eq!(
+            synced.into_iter_sorted().collect::<Vec<_>>(),
+            new.into_iter_sorted().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn it_shrinks_to_fit() {
+        let mut list = ExpirationList::new();
+        for idx in 0..1000 {
+            list.add(idx);
+        }
+        // Removing 49% of the items never triggers a shrink
+        for idx in 0..490 {
+            list.remove(idx);
+        }
+        assert_eq!(list.first_id, 0);
+        let capacity = list.capacity();
+
+        list.shrink_to_fit();
+        assert_eq!(list.first_id, 500);
+        assert!(list.capacity() < capacity);
+        assert_eq!(list.list.capacity(), 500);
+        assert_eq!(list.map.len(), 10);
+        for id in 0..1000 {
+            assert_eq!(list.get(id), (id >= 490).then_some(&id));
+        }
+        assert_eq!(list.add(1000), 1000);
+
+        // Removing every other item leaves no empty slots at the front, but still reclaims memory
+        let mut list = ExpirationList::new();
+        for idx in 0..1000 {
+            list.add(idx);
+        }
+        for idx in (0..1000).step_by(2) {
+            list.remove(idx);
+        }
+        assert_eq!(list.first_id, 0);
+        let capacity = list.capacity();
+        list.shrink_to_fit();
+        assert_eq!(list.first_id, 501);
+        assert!(list.capacity() < capacity);
+        assert_eq!(list.len(), 500);
+        for id in 0..1000 {
+            assert_eq!(list.get(id), (id % 2 == 1).then_some(&id));
+        }
+        assert_eq!(list.add(1000), 1000);
+
+        // A list with only removed items in the `Vec` collapses, keeping the `HashMap`
+        let mut list = ExpirationList::new();
+        for idx in 0..64 {
+            list.add(idx);
+        }
+        for idx in (0..64).filter(|idx| *idx != 3) {
+            list.remove(idx);
+        }
+        assert_eq!(list.map.len(), 1);
+        list.shrink_to_fit();
+        assert_eq!(list.list.capacity(), 0);
+        assert_eq!(list.get(3), Some(&3));
+        assert_eq!(list.add(64), 64);
+    }
+
+    #[test]
+    fn it_shrinks_in_place() {
+        fn largest_alloc_during_shrink(mut list: ExpirationList<[u64; 32]>) -> usize {
+            for idx in 0..1024 {
+                list.add([idx; 32]);
+            }
+            for idx in 0..512 {
+                list.remove(idx);
+            }
+            assert_eq!(list.first_id, 0);
+            LARGEST_ALLOC.with(|cell| cell.set(0));
+            list.remove(512);
+            let largest = LARGEST_ALLOC.with(Cell::get);
+            assert_eq!(list.first_id, 512);
+            assert_eq!(list.get(600), Some(&[600; 32]));
+            assert_eq!(list.validate(), Ok(()));
+            return largest;
+        }
+
+        let slots_size = 1024 * std::mem::size_of::<Option<[u64; 32]>>();
+        assert!(largest_alloc_during_shrink(ExpirationList::new()) >= slots_size);
+        assert!(
+            largest_alloc_during_shrink(ExpirationList::with_in_place_shrink()) < slots_size / 2
+        );
+    }
+
+    #[test]
+    fn it_clones_with_the_same_ids() {
+        let mut list = sparse_list();
+        let mut clone = list.clone();
+        for id in 0..64 {
+            assert_eq!(clone.get(id), list.get(id));
+        }
+        assert_eq!(clone.len(), list.len());
+        assert_eq!(clone.add(64), list.add(64));
+        clone.remove(63);
+        assert_eq!(list.get(63), Some(&63));
+    }
+
+    #[test]
+    fn it_snapshots_matching_items() {
+        let list = sparse_list();
+        let snapshot = list.snapshot_filtered(|id, _| id % 2 == 0);
+        let expected: Vec<(usize, usize)> = (0..64).step_by(6).map(|id| (id, id)).collect();
+        assert_eq!(snapshot, expected);
+        assert_eq!(list.len(), 22);
+        assert_eq!(list.get(3), Some(&3));
+    }
+
+    #[test]
+    fn it_compares_by_contents() {
+        let mut shrunk = ExpirationList::new();
+        for idx in 0..64 {
+            shrunk.add(idx);
+            if idx % 4 != 0 {
+                shrunk.remove(idx);
+            }
+        }
+        let mut other = ExpirationList::new();
+        for idx in 0..64 {
+            other.add(idx);
+        }
+        for idx in (0..64).filter(|idx| idx % 4 != 0) {
+            other.take(idx);
+        }
+        assert_ne!(shrunk.first_id, other.first_id);
+        assert_eq!(shrunk, other);
+
+        other.set(4, 40);
+        assert_ne!(shrunk, other);
+        other.set(4, 4);
+        other.add(64);
+        assert_ne!(shrunk, other);
+        assert_eq!(ExpirationList::<i32>::new(), ExpirationList::new());
+    }
+
+    #[test]
+    fn it_finds_the_first_free_id() {
+        let mut list = ExpirationList::with_free_list();
+        for idx in 0..10 {
+            list.add(idx);
+        }
+        assert_eq!(list.first_free_id(), None);
+        list.remove(7);
+        list.remove(4);
+        assert_eq!(list.first_free_id(), Some(4));
+        assert_eq!(list.add(40), 4);
+        assert_eq!(list.first_free_id(), Some(7));
+        assert_eq!(list.add(70), 7);
+        assert_eq!(list.first_free_id(), None);
+    }
 
     #[test]
-    fn it_counts_elements() {
+    fn it_preallocates_with_capacity() {
+        let empty = ExpirationList::<usize>::with_capacity(0);
+        assert_eq!(empty.capacity(), ExpirationList::<usize>::new().capacity());
+        assert_eq!(empty, ExpirationList::new());
+
+        let mut list = ExpirationList::with_capacity(1000);
+        let capacity = list.capacity();
+        assert!(capacity >= 1000);
+        for idx in 0..1000 {
+            assert_eq!(list.add(idx), idx);
+            assert_eq!(list.capacity(), capacity);
+        }
+    }
+
+    #[test]
+    fn it_batches_by_weight() {
         let mut list = ExpirationList::new();
-        for idx in 0..1024 {
+        for idx in 0..64 {
+            list.add(vec![0u8; idx % 7 * 10]);
+        }
+        list.add(vec![0u8; 500]);
+        for idx in (0..64).filter(|idx| idx % 3 == 1) {
+            list.remove(idx);
+        }
+
+        let batches: Vec<Vec<(usize, &Vec<u8>)>> =
+            list.weighted_batches(|value| value.len(), 100).collect();
+        for batch in &batches {
+            let total: usize = batch.iter().map(|(_, value)| value.len()).sum();
+            assert!(total <= 100 || batch.len() == 1);
+        }
+        assert_eq!(batches.last().unwrap().len(), 1);
+        let ids: Vec<usize> = batches.into_iter().flatten().map(|(id, _)| id).collect();
+        let expected: Vec<usize> = list.iter_range(..).map(|(id, _)| id).collect();
+        assert_eq!(ids, expected);
+
+        // Each item is weighed once
+        let weighed = core::cell::Cell::new(0);
+        let weigh = |value: &Vec<u8>| {
+            weighed.set(weighed.get() + 1);
+            return value.len();
+        };
+        assert_eq!(
+            list.weighted_batches(weigh, 100).flatten().count(),
+            list.len()
+        );
+        assert_eq!(weighed.get(), list.len());
+
+        // Totals that overflow never share a batch, even with the largest `max_weight`
+        for max_weight in [100, usize::MAX] {
+            let batches: Vec<Vec<(usize, &Vec<u8>)>> =
+                list.weighted_batches(|_| usize::MAX, max_weight).collect();
+            assert_eq!(batches.len(), list.len());
+        }
+    }
+
+    #[test]
+    fn it_indexes_by_id() {
+        let mut list = sparse_list();
+
+        assert_eq!(list[0], 0);
+        assert_eq!(list[63], 63);
+        list[0] += 100;
+        list[63] += 100;
+        assert_eq!(list.get(0), Some(&100));
+        assert_eq!(list.get(63), Some(&163));
+    }
+
+    #[test]
+    #[should_panic(expected = "no item with id 62")]
+    fn it_panics_when_indexing_a_removed_id() {
+        let mut list = ExpirationList::new();
+        for idx in 0..64 {
             list.add(idx);
         }
-        assert_eq!(list.count, 1024);
+        list.remove(62);
+        let _ = list[62];
+    }
 
-        for idx in 0..513 {
+    #[test]
+    #[should_panic(expected = "no item with id 1")]
+    fn it_panics_when_indexing_mutably_below_the_vec() {
+        let mut list = ExpirationList::new();
+        for idx in 0..64 {
+            list.add(idx);
+        }
+        for idx in 0..40 {
             list.remove(idx);
         }
-        assert_eq!(list.count, 1024 - 513);
+        assert!(list.first_id > 1);
+        list[1] = 1;
+    }
 
-        for idx in 0..260 {
-            list.remove(idx + 600); // 513 - 599 should be not removed
+    #[test]
+    fn it_reports_when_compact_and_shrink_do_nothing() {
+        let mut list = ExpirationList::new();
+        for idx in 0..64 {
+            list.add(idx);
         }
-        assert_eq!(list.count, 1024 - 513 - 260 - (600 - 513));
-        assert_eq!(list.map.len(), 600 - 513);
+        for idx in (0..64).filter(|idx| idx % 3 != 0) {
+            list.take(idx);
+        }
+        assert!(list.shrink_now());
+        assert!(!list.shrink_now());
 
-        // Check that the list has been reduced to 256 items
-        assert_eq!(list.first_id, 512 + 256);
-        assert_eq!(list.list.len(), 256);
+        // A shrink capped before moving any slots reports nothing and leaves the state alone
+        let map = [(0, 0)].into_iter().collect();
+        let slots = (100..200).map(|idx| (idx >= 160).then_some(idx)).collect();
+        let mut capped = ExpirationList::from_parts(100, slots, map);
+        capped.map_ratio_cap = Some(0.001);
+        let state = (capped.shrink_armed, capped.last_shrink_len);
+        assert!(!capped.shrink_now());
+        assert_eq!(capped.first_id, 100);
+        assert_eq!((capped.shrink_armed, capped.last_shrink_len), state);
+
+        assert!(list.compact());
+        let capacity = list.capacity();
+        assert!(!list.compact());
+        assert!(list.map.is_empty());
+        assert_eq!(list.capacity(), capacity);
     }
 
     #[test]
-    fn it_adds_and_gets() {
+    fn it_gets_or_adds() {
         let mut list = ExpirationList::new();
-        for idx in 0..1024 {
+        for idx in 0..4 {
             list.add(idx);
         }
-        for idx in 0..1024 {
+        let (id, value) = list.get_or_add(2, || 20);
+        assert_eq!((id, *value), (2, 2));
+        *value = 200;
+        assert_eq!(list.get(2), Some(&200));
+
+        list.remove(3);
+        let (id, value) = list.get_or_add(3, || 30);
+        assert_eq!((id, *value), (4, 30));
+        assert_eq!(list.get(3), None);
+        assert_eq!(list.get(4), Some(&30));
+        assert_eq!(list.len(), 4);
+    }
+
+    #[test]
+    fn it_tracks_ids_without_values() {
+        assert_eq!(std::mem::size_of::<Option<()>>(), 1);
+
+        let mut list = ExpirationList::new();
+        for idx in 0..1000 {
+            assert_eq!(list.add(()), idx);
+        }
+        for idx in (0..1000).filter(|idx| idx % 5 != 0) {
+            assert_eq!(list.remove(idx), Some(()));
+        }
+        assert!(list.first_id > 0);
+        for id in 0..1000 {
+            assert_eq!(list.contains(id), id % 5 == 0);
+        }
+        let ids: Vec<usize> = list.iter_range(..).map(|(id, _)| id).collect();
+        assert_eq!(ids, (0..1000).step_by(5).collect::<Vec<_>>());
+        assert!(list.list.capacity() <= 1000);
+    }
+
+    #[test]
+    fn it_drains_every_item() {
+        let mut list = sparse_list();
+        let capacity = list.list.capacity();
+
+        let mut drained: Vec<(usize, usize)> = list.drain().collect();
+        drained.sort_unstable();
+        let expected: Vec<(usize, usize)> = (0..64).step_by(3).map(|id| (id, id)).collect();
+        assert_eq!(drained, expected);
+        assert!(list.is_empty());
+        assert_eq!(list.list.capacity(), capacity);
+        assert_eq!(list.add(64), 64);
+
+        // Dropping the iterator early still removes everything
+        for idx in 0..10 {
+            list.add(idx);
+        }
+        assert_eq!(list.drain().take(3).count(), 3);
+        assert!(list.is_empty());
+        assert_eq!(list.count, 0);
+        assert_eq!(list.get(70), None);
+        assert_eq!(list.validate(), Ok(()));
+    }
+
+    #[test]
+    fn it_retains_within_a_range() {
+        let mut list = sparse_list();
+        assert!(list.first_id > 0 && !list.map.is_empty());
+
+        let mut visited = Vec::new();
+        list.retain_in_range(10..50, |id, _| {
+            visited.push(id);
+            false
+        });
+        visited.sort_unstable();
+        assert_eq!(visited, (12..50).step_by(3).collect::<Vec<_>>());
+        for id in (0..64).step_by(3) {
+            assert_eq!(list.contains(id), !(10..50).contains(&id));
+        }
+
+        list.retain_in_range(60.., |_, _| false);
+        assert_eq!(list.get(60), None);
+        assert_eq!(list.get(63), None);
+        assert_eq!(list.get(9), Some(&9));
+        assert_eq!(list.validate(), Ok(()));
+    }
+
+    #[test]
+    fn it_knows_how_many_items_the_iterators_have_left() {
+        let mut list = sparse_list();
+
+        assert_eq!((&list).into_iter().len(), list.len());
+        let mut iter = (&list).into_iter();
+        for remaining in (0..list.len()).rev() {
+            iter.next();
+            assert_eq!(iter.size_hint(), (remaining, Some(remaining)));
+        }
+        assert_eq!(iter.next(), None);
+
+        let len = list.len();
+        let mut iter = list.iter_mut();
+        iter.nth(len - 2);
+        assert_eq!(iter.len(), 1);
+        let mut iter = list.into_iter();
+        iter.next();
+        assert_eq!(iter.len(), len - 1);
+    }
+
+    #[test]
+    fn it_keeps_returning_none_after_iterating() {
+        fn assert_fused<I: FusedIterator>(iter: I) -> I {
+            return iter;
+        }
+
+        let list: ExpirationList<usize> = ExpirationList::new();
+        let mut iter = assert_fused((&list).into_iter());
+        for _ in 0..3 {
+            assert_eq!(iter.next(), None);
+        }
+
+        let mut list = sparse_list();
+        let mut iter = assert_fused(list.iter_mut());
+        assert_eq!(iter.by_ref().count(), 22);
+        for _ in 0..3 {
+            assert_eq!(iter.next(), None);
+        }
+        let mut iter = assert_fused(list.into_iter());
+        assert_eq!(iter.by_ref().count(), 22);
+        for _ in 0..3 {
+            assert_eq!(iter.next(), None);
+        }
+    }
+
+    #[test]
+    fn it_iterates_keys_and_values() {
+        let mut list = sparse_list();
+        for (id, value) in list.iter_mut() {
+            *value = id * 10;
+        }
+
+        let mut keys: Vec<usize> = list.keys().collect();
+        keys.sort_unstable();
+        assert_eq!(keys, (0..64).step_by(3).collect::<Vec<_>>());
+        assert!(keys.iter().all(|id| list.get(*id).is_some()));
+        assert_eq!(list.values().count(), list.len());
+        assert_eq!(
+            list.values().sum::<usize>(),
+            keys.iter().sum::<usize>() * 10
+        );
+    }
+
+    #[test]
+    fn it_mutates_a_range() {
+        let mut list = sparse_list();
+        assert!(list.first_id > 10 && list.first_id < 50);
+
+        let mut ids = Vec::new();
+        for (id, value) in list.range_mut(10..=50) {
+            *value += 1000;
+            ids.push(id);
+        }
+        assert_eq!(ids, (12..=48).step_by(3).collect::<Vec<_>>());
+        for id in (0..64).step_by(3) {
+            let expected = if (10..=50).contains(&id) {
+                id + 1000
+            } else {
+                id
+            };
+            assert_eq!(list.get(id), Some(&expected));
+        }
+        assert_eq!(list.range_mut(64..).count(), 0);
+        assert_eq!(list.range_mut(100..).count(), 0);
+        #[allow(clippy::reversed_empty_ranges)]
+        let inverted = 50..10;
+        assert_eq!(list.range_mut(inverted).count(), 0);
+        assert_eq!(list.range_mut(..).count(), list.len());
+    }
+
+    #[cfg(feature = "serde_support")]
+    #[test]
+    fn it_serializes_only_live_items() {
+        let mut list = ExpirationList::with_in_place_shrink();
+        for idx in 0..1000 {
+            list.add(idx);
+        }
+        for idx in (0..1000).filter(|idx| idx % 50 != 0) {
+            list.remove(idx);
+        }
+        let json = serde_json::to_string(&list).unwrap();
+        assert!(json.len() < 16 * list.len() + 256);
+
+        let mut restored: ExpirationList<usize> = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored, list);
+        for idx in (0..1000).step_by(50) {
+            assert_eq!(restored.get(idx), Some(&idx));
+        }
+        assert_eq!(restored.add(1000), list.add(1000));
+    }
+
+    #[test]
+    fn it_counts_items_by_key() {
+        #[derive(Debug, PartialEq, Eq, Hash)]
+        enum State {
+            Idle,
+            Busy,
+            Done,
+        }
+        let state = |value: &usize| match value % 3 {
+            0 => State::Idle,
+            1 => State::Busy,
+            _ => State::Done,
+        };
+        let mut list = ExpirationList::new();
+        for idx in 0..100 {
+            list.add(idx);
+        }
+        for idx in (0..60).step_by(2) {
+            list.remove(idx);
+        }
+        let histogram = list.histogram_by(state);
+        assert_eq!(histogram.values().sum::<usize>(), list.len());
+        for key in [State::Idle, State::Busy, State::Done] {
+            let manual = (&list)
+                .into_iter()
+                .filter(|(_, value)| state(value) == key)
+                .count();
+            assert_eq!(histogram.get(&key), Some(&manual));
+        }
+    }
+
+    #[test]
+    fn it_reserves_ahead_of_a_burst() {
+        let mut list = ExpirationList::new();
+        for idx in 0..10 {
+            list.add(idx);
+        }
+        list.reserve(100);
+        let capacity = list.capacity();
+        for idx in 0..100 {
+            list.add(idx);
+            assert_eq!(list.capacity(), capacity);
+        }
+        list.reserve_exact(0);
+        assert_eq!(list.capacity(), capacity);
+    }
+
+    #[test]
+    fn it_reports_the_shrink_visit_ratio() {
+        let mut oldest_first = ExpirationList::new();
+        for idx in 0..1000 {
+            oldest_first.add(idx);
+        }
+        assert_eq!(oldest_first.shrink_visit_ratio(), 0.0);
+        for idx in 0..1000 {
+            oldest_first.remove(idx);
+        }
+        assert!(oldest_first.shrink_visit_ratio() < 2.5);
+
+        // Setting far away IDs pads the `Vec`, so every removal shrinks away the padding
+        let mut sparse_ids = ExpirationList::new();
+        for idx in 0..100 {
+            let id = sparse_ids.next_id() + 100;
+            sparse_ids.set(id, idx);
+            sparse_ids.remove(id);
+        }
+        assert!(sparse_ids.shrink_visit_ratio() > 50.0);
+    }
+
+    #[test]
+    fn it_never_shrinks_with_a_zero_fill_ratio() {
+        let mut list = ExpirationList::with_policy(ShrinkPolicy {
+            min_len: 32,
+            fill_ratio: 0.0,
+        });
+        for idx in 0..1000 {
+            list.add(idx);
+        }
+        for idx in 0..990 {
+            list.remove(idx);
+        }
+        assert!(list.map.is_empty());
+        assert_eq!(list.first_id, 0);
+        assert_eq!(list.get(995), Some(&995));
+    }
+
+    #[test]
+    fn it_shrinks_tiny_lists_with_a_zero_floor() {
+        let mut list = ExpirationList::with_policy(ShrinkPolicy {
+            min_len: 0,
+            fill_ratio: 1.0,
+        });
+        for idx in 0..8 {
+            list.add(idx);
+        }
+        list.remove(0);
+        assert!(list.first_id > 0);
+        for idx in 1..8 {
             assert_eq!(list.get(idx), Some(&idx));
         }
+
+        // A single slot shrinks too, with or without shrinking in place
+        for in_place in [false, true] {
+            let mut list = ExpirationList::with_policy(ShrinkPolicy {
+                min_len: 0,
+                fill_ratio: 1.0,
+            });
+            list.in_place_shrink = in_place;
+            list.add(0);
+            assert_eq!(list.remove_profiled(0), Some((0, true)));
+            assert_eq!(list.first_id, 1);
+            assert!(list.list.is_empty());
+            assert_eq!(list.add(1), 1);
+        }
     }
 
     #[test]
-    fn it_gets_after_remove() {
+    fn it_zips_items_by_id() {
+        let mut positions = ExpirationList::new();
+        let mut names = ExpirationList::new();
+        for idx in 0..100 {
+            positions.add(idx * 10);
+            names.add(format!("item {idx}"));
+        }
+        for idx in 0..100 {
+            if idx % 2 == 0 {
+                positions.remove(idx);
+            }
+            if idx % 3 == 0 {
+                names.remove(idx);
+            }
+        }
+        let zipped: Vec<(usize, usize, String)> = positions
+            .zip_ids(&names)
+            .map(|(id, position, name)| (id, *position, name.clone()))
+            .collect();
+        let expected: Vec<(usize, usize, String)> = (0..100)
+            .filter(|idx| idx % 2 != 0 && idx % 3 != 0)
+            .map(|idx| (idx, idx * 10, format!("item {idx}")))
+            .collect();
+        assert_eq!(zipped, expected);
+    }
+
+    #[test]
+    fn it_gets_disjoint_items_mutably() {
         let mut list = ExpirationList::new();
-        for idx in 0..1024 {
+        for idx in 0..100 {
             list.add(idx);
         }
-        for idx in 0..513 {
-            assert_eq!(list.remove(idx), Some(idx as i32));
+        for idx in 0..80 {
+            if idx != 10 {
+                list.remove(idx);
+            }
         }
-        assert_eq!(list.get(100), None);
-        assert_eq!(list.remove(100), None);
-        assert_eq!(list.get(600), Some(&600));
-        assert_eq!(list.remove(600), Some(600));
-        assert_eq!(list.get(600), None);
+        assert_eq!(list.residence(10), Some(Region::Map));
+        assert_eq!(list.residence(90), Some(Region::List));
 
-        list.into_iter();
+        let [old, new, newest] = list.get_disjoint_mut([10, 90, 99]).unwrap();
+        std::mem::swap(old, new);
+        *newest += 1000;
+        assert_eq!(list.get(10), Some(&90));
+        assert_eq!(list.get(90), Some(&10));
+        assert_eq!(list.get(99), Some(&1099));
+
+        assert!(list.get_disjoint_mut([10, 90, 10]).is_none());
+        assert!(list.get_disjoint_mut([10, 11]).is_none());
+        assert!(list.get_disjoint_mut([90, 1000]).is_none());
+        assert_eq!(list.get_disjoint_mut([]), Some([]));
     }
 
     #[test]
-    fn it_is_iterable() {
+    fn it_compacts_after_removing_most_of_the_map() {
         let mut list = ExpirationList::new();
-        for idx in 0..10_000 {
-            list.add(idx as i32);
+        for idx in 0..1000 {
+            list.add(idx);
+        }
+        for idx in (0..1000).step_by(2) {
             list.remove(idx);
         }
-        for idx in 0..10 {
+        list.remove(1);
+        let map_len = list.map.len();
+        assert!(map_len > 200);
+
+        let mut map_ids: Vec<usize> = list.map.keys().copied().collect();
+        map_ids.sort_unstable();
+        let mut removed = 0;
+        while !list.map.is_empty() {
+            list.remove(map_ids[removed]);
+            removed += 1;
+        }
+        assert!(removed < map_len);
+        assert!(list.first_id < 500);
+        assert!(list.map.capacity() < 32);
+        for id in &map_ids[removed..] {
+            assert_eq!(list.get(*id), Some(id));
+        }
+    }
+
+    #[test]
+    fn it_applies_every_setting_in_a_config() {
+        let config = Config {
+            shrink_policy: ShrinkPolicy {
+                min_len: 8,
+                fill_ratio: 0.5,
+            },
+            hysteresis: Some((0.5, 0.6)),
+            map_ratio_cap: Some(1.0),
+            in_place_shrink: true,
+            auto_compact: Some(4),
+            free_list: true,
+        };
+        let mut list = ExpirationList::with_config(config);
+        assert_eq!(list.config(), config);
+        for idx in 0..16 {
             list.add(idx);
         }
-        list.remove(10_004);
-        list.remove(10_007);
-        list.remove(10_008);
+        let capacity = list.list.capacity();
+        for idx in (0..10).filter(|idx| *idx != 2) {
+            list.remove(idx);
+        }
+        // The `Vec` is shorter than 32 slots but still shrank, in place
+        assert_eq!(list.first_id, 8);
+        assert_eq!(list.list.capacity(), capacity);
+        assert_eq!(list.residence(2), Some(Region::Map));
+        assert!(list.map.len() as f64 <= 1.0 * list.list.len() as f64);
 
-        let mut result: Vec<i32> = Vec::new();
-        for (key, value) in &list {
-            assert_eq!(key as i32 - 10_000, *value);
-            result.push(*value);
+        // The free list reuses the lowest removed ID
+        assert_eq!(list.add(100), 8);
+
+        // Lookups in the `HashMap` compact it
+        for _ in 0..4 {
+            list.get_mut(2);
+        }
+        list.get_mut(2);
+        assert!(list.map.is_empty());
+        assert_eq!(list.get(2), Some(&2));
+    }
+
+    #[test]
+    fn it_adds_many_items_with_contiguous_ids() {
+        let mut list = ExpirationList::new();
+        list.add(0);
+        let ids = list.add_many(0..500);
+        assert_eq!(ids.len(), 500);
+        assert_eq!(list.get(ids.start), Some(&0));
+        assert_eq!(list.get(ids.start + 250), Some(&250));
+        assert_eq!(list.get(ids.end - 1), Some(&499));
+        assert_eq!(list.get(ids.end), None);
+        assert_eq!(list.add_many(std::iter::empty()), ids.end..ids.end);
+    }
+
+    #[test]
+    fn it_converts_into_a_hashmap() {
+        let mut list = ExpirationList::new();
+        for idx in 0..100 {
+            list.add(idx * 10);
         }
+        for idx in (0..90).filter(|idx| idx % 7 != 0) {
+            list.remove(idx);
+        }
+        assert!(!list.map.is_empty());
+        let expected: Vec<(usize, usize)> = (0..100)
+            .filter_map(|id| Some((id, *list.get(id)?)))
+            .collect();
 
-        assert_eq!(result, vec![0, 1, 2, 3, 5, 6, 9]);
+        let map = list.into_hashmap();
+        assert_eq!(map.len(), expected.len());
+        for (id, value) in expected {
+            assert_eq!(map.get(&id), Some(&value));
+        }
     }
 
     #[test]
-    fn it_removes_multiple_times() {
+    fn it_keeps_pinned_ids_in_the_list() {
         let mut list = ExpirationList::new();
-        for idx in 0..10 {
+        for idx in 0..200 {
             list.add(idx);
         }
-        assert_eq!(list.remove(1), Some(1));
-        for _ in 0..10 {
-            assert_eq!(list.remove(1), None);
+        list.pin_range(50..60);
+        for idx in (0..200).filter(|idx| !(50..60).contains(idx) && idx % 10 != 0) {
+            list.remove(idx);
+            for id in 50..60 {
+                assert_eq!(list.residence(id), Some(Region::List));
+            }
         }
-        for idx in 0..10 {
+        assert!(list.first_id > 0);
+        assert!(list.first_id <= 50);
+        list.shrink_to_fit();
+        assert_eq!(list.residence(50), Some(Region::List));
+
+        assert!(list.unpin_range(50..60));
+        assert!(!list.unpin_range(50..60));
+        list.remove(59);
+        assert_eq!(list.residence(50), Some(Region::Map));
+    }
+
+    #[test]
+    fn it_reports_running_out_of_ids() {
+        let mut list = ExpirationList::with_capacity_and_first_id(4, usize::MAX - 2);
+        assert_eq!(list.try_add(0), Ok(usize::MAX - 2));
+        assert_eq!(list.try_add(1), Ok(usize::MAX - 1));
+        assert_eq!(list.next_id(), usize::MAX);
+        assert_eq!(list.try_add(2), Err(CapacityError));
+        assert_eq!(list.len(), 2);
+        assert_eq!(list.get(usize::MAX - 1), Some(&1));
+    }
+
+    #[test]
+    #[should_panic(expected = "no IDs are left")]
+    fn it_panics_when_adding_past_the_last_id() {
+        let mut list = ExpirationList::with_capacity_and_first_id(0, usize::MAX);
+        list.add(0);
+    }
+
+    #[test]
+    fn it_copies_the_values_into_a_vec() {
+        let mut list = ExpirationList::new();
+        for idx in 0..100 {
             list.add(idx);
         }
-        assert_eq!(list.count, 19);
+        for idx in (0..80).filter(|idx| idx % 3 != 0) {
+            list.remove(idx);
+        }
+        assert!(!list.map.is_empty());
+        let values = list.to_vec();
+        let expected: Vec<usize> = (0..100).filter(|idx| *idx >= 80 || idx % 3 == 0).collect();
+        assert_eq!(values, expected);
+        assert_eq!(values.capacity(), list.len());
+    }
+
+    #[test]
+    fn it_removes_many_items_with_one_shrink() {
+        let mut list = ExpirationList::new();
+        for idx in 0..1000 {
+            list.add(idx);
+        }
+        assert_eq!(list.remove_many((0..400).chain([10, 10, 5000])), 400);
+        assert_eq!(list.len(), 600);
+        assert_eq!(list.first_id, 0);
+
+        assert_eq!(list.remove_many(400..600), 200);
+        assert_eq!(list.len(), 400);
+        assert_eq!(list.first_id, 500);
+        assert_eq!(list.shrink_visits, 1000);
+        assert_eq!(list.get(600), Some(&600));
+    }
+
+    #[test]
+    fn it_collects_and_extends_values() {
+        let mut list: ExpirationList<usize> = (0..100).collect();
+        assert_eq!(list.len(), 100);
+        assert_eq!(list.get(0), Some(&0));
+        assert_eq!(list.get(99), Some(&99));
+
+        let mut added = ExpirationList::new();
+        for idx in 0..100 {
+            added.add(idx);
+        }
+        assert_eq!(list, added);
+
+        list.extend(100..150);
+        assert_eq!(list.len(), 150);
+        assert_eq!(list.get(100), Some(&100));
+        assert_eq!(list.get(149), Some(&149));
+        assert_eq!(list.next_id(), 150);
+    }
+
+    #[test]
+    fn it_is_send_sync_and_unwind_safe() {
+        fn assert_traits<L: Send + Sync + UnwindSafe + RefUnwindSafe>() {}
+        assert_traits::<ExpirationList<i32>>();
+        assert_traits::<ExpirationList<String, std::hash::RandomState>>();
+    }
+
+    #[test]
+    fn it_reports_running_out_of_ids_in_bulk_adds() {
+        let mut list = ExpirationList::with_capacity_and_first_id(0, usize::MAX - 1);
+        assert_eq!(
+            list.try_add_iter(0..3).unwrap_err().error,
+            TryAddCause::Capacity(CapacityError)
+        );
+        assert_eq!(list.len(), 1);
+        assert_eq!(list.next_id(), usize::MAX);
+        assert_eq!(list.try_add(1), Err(CapacityError));
+
+        for bulk_add in [
+            |list: &mut ExpirationList<i32>| {
+                list.add_many(0..3);
+            },
+            |list: &mut ExpirationList<i32>| list.extend(0..3),
+            |list: &mut ExpirationList<i32>| {
+                list.append_from_slice(&[0, 1, 2]);
+            },
+            |list: &mut ExpirationList<i32>| list.resize_with(3, || 0),
+        ] {
+            let panicked = std::panic::catch_unwind(|| {
+                let mut list = ExpirationList::with_capacity_and_first_id(0, usize::MAX - 1);
+                bulk_add(&mut list);
+            });
+            assert!(panicked.is_err());
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "residue below the modulus")]
+    fn it_rejects_a_stride_residue_past_the_modulus() {
+        let mut list = ExpirationList::new();
+        for idx in 0..64 {
+            list.add(idx);
+        }
+        for idx in (0..40).filter(|idx| *idx != 10) {
+            list.remove(idx);
+        }
+        assert!(list.map.contains_key(&10));
+        let _ = list.iter_stride(4, 4);
     }
 }